@@ -0,0 +1,123 @@
+use proc_macro2::Span;
+use syn::{ parse_quote,
+           visit_mut::{ self, VisitMut } };
+
+use crate::helpers::{ Errors, hygienic_ident };
+
+// What a `?` in the traced body has to turn into, going by the last
+// segment of the function's return type.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ReturnKind {
+    // `Option<T>`
+    Option,
+    // `Result<T, E>`, or an alias like `io::Result<T>`/`TcResult<T>`
+    // whose name ends in `Result`.
+    Result,
+    Other,
+}
+
+impl ReturnKind {
+    pub fn of(return_type : &syn::Type) -> ReturnKind {
+        let last_segment = match return_type {
+            syn::Type::Path(type_path) => type_path.path.segments.last(),
+            _ => None
+        };
+
+        match last_segment.map(|seg| seg.ident.to_string()) {
+            Some(ref name) if name == "Option" => ReturnKind::Option,
+            Some(ref name) if name.ends_with("Result") => ReturnKind::Result,
+            _ => ReturnKind::Other
+        }
+    }
+}
+
+// True if `impl Trait` appears anywhere in the type, in which case the
+// result binding can't be annotated with it.
+pub fn contains_impl_trait(ty : &syn::Type) -> bool {
+    struct FindImpl(bool);
+
+    impl VisitMut for FindImpl {
+        fn visit_type_impl_trait_mut(&mut self, _ : &mut syn::TypeImplTrait) {
+            self.0 = true
+        }
+    }
+
+    let mut find_impl = FindImpl(false);
+    find_impl.visit_type_mut(&mut ty.clone());
+    find_impl.0
+}
+
+// The label of the block the body is moved into with `body = block`.
+pub fn body_label() -> syn::Lifetime {
+    syn::Lifetime::new("'traced_body", Span::mixed_site())
+}
+
+// Rewrites the early exits of a body that is moved into a labeled block
+// instead of a closure : `return e` becomes `break 'traced_body e`, and
+// `e?` becomes a match that breaks out with the `Err`/`None`.
+// Closures, async blocks and nested items have their own `return`s and
+// are left alone. Exits hidden inside macro invocations can't be seen;
+// they skip the epilogue and get recorded like an unwind.
+pub struct EarlyExitRewriter {
+    pub label : syn::Lifetime,
+    pub return_kind : ReturnKind,
+    pub errors : Errors,
+}
+
+impl EarlyExitRewriter {
+    pub fn new(return_kind : ReturnKind) -> Self {
+        EarlyExitRewriter {
+            label : body_label(),
+            return_kind,
+            errors : Errors::default(),
+        }
+    }
+}
+
+impl VisitMut for EarlyExitRewriter {
+    fn visit_expr_mut(&mut self, e : &mut syn::Expr) {
+        let label = self.label.clone();
+        match e {
+            syn::Expr::Closure(..) | syn::Expr::Async(..) => (),
+            syn::Expr::Return(syn::ExprReturn { attrs, expr, .. }) => {
+                if let Some(inner) = expr {
+                    self.visit_expr_mut(inner);
+                }
+                let value = expr.as_ref().map(|inner| quote::quote!(#inner)).unwrap_or_else(|| quote::quote!(()));
+                *e = parse_quote! { #(#attrs)* break #label #value };
+            },
+            syn::Expr::Try(syn::ExprTry { attrs, expr, question_token }) => {
+                self.visit_expr_mut(expr);
+                let value = hygienic_ident("value");
+                let error = hygienic_ident("error");
+                match self.return_kind {
+                    ReturnKind::Result => {
+                        *e = parse_quote! {
+                            #(#attrs)*
+                            match #expr {
+                                Ok(#value) => #value,
+                                Err(#error) => break #label Err(From::from(#error)),
+                            }
+                        };
+                    },
+                    ReturnKind::Option => {
+                        *e = parse_quote! {
+                            #(#attrs)*
+                            match #expr {
+                                Some(#value) => #value,
+                                None => break #label None,
+                            }
+                        };
+                    },
+                    ReturnKind::Other => {
+                        self.errors.push(syn::Error::new_spanned(question_token, "`body = block` can only rewrite `?` in \
+                                                                                   functions returning `Option` or `Result`"));
+                    }
+                }
+            },
+            _ => visit_mut::visit_expr_mut(self, e)
+        }
+    }
+
+    fn visit_item_mut(&mut self, _ : &mut syn::Item) {}
+}
@@ -22,8 +22,40 @@ pub fn snake_case_name(ident : &syn::Ident) -> syn::Ident {
     syn::Ident::new(acc.as_str(), ident.span())
 }
 
+// Accumulates errors so a macro can report every problem it finds in one
+// go instead of stopping at the first one.
+#[derive(Default)]
+pub struct Errors(Option<syn::Error>);
 
-pub fn fold_with<T>(mut v : Vec<T>, t : T) -> Vec<T> {
-    v.push(t);
-    v
-}
\ No newline at end of file
+impl Errors {
+    pub fn push(&mut self, e : syn::Error) {
+        match &mut self.0 {
+            Some(acc) => acc.combine(e),
+            None => self.0 = Some(e)
+        }
+    }
+
+    // Records the error (if any) and hands back the success value.
+    pub fn take<T>(&mut self, r : syn::Result<T>) -> Option<T> {
+        match r {
+            Ok(t) => Some(t),
+            Err(e) => {
+                self.push(e);
+                None
+            }
+        }
+    }
+
+    pub fn finish(self) -> syn::Result<()> {
+        match self.0 {
+            Some(e) => Err(e),
+            None => Ok(())
+        }
+    }
+}
+
+// A local for generated code that the user's tokens can't refer to
+// (or be captured by), thanks to mixed-site hygiene.
+pub fn hygienic_ident(name : &str) -> syn::Ident {
+    syn::Ident::new(name, proc_macro2::Span::mixed_site())
+}
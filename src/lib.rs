@@ -2,7 +2,6 @@
 #![allow(unused_mut)]
 #![allow(unused_variables)]
 #![allow(unused_imports)]
-#![allow(dead_code)]
 #![allow(unreachable_code)]
 extern crate proc_macro;
 use proc_macro::TokenStream;
@@ -13,268 +12,1009 @@ use quote::{ quote, format_ident };
 use syn::{ parse_macro_input, 
            parse_quote, 
            parse::Parse,
+           parse::Parser,
            parse::ParseStream,
            parse::Result,
+           ext::IdentExt,
            visit_mut::VisitMut, 
            ItemFn, 
            punctuated::Punctuated,
            Stmt };
 
+mod block_body;
 mod helpers;
+mod paths;
+mod step_checker;
+mod step_codec;
 mod step_derive;
+mod step_display;
+mod step_json;
+mod step_kind;
+mod step_visitor;
+
+use crate::block_body::{ EarlyExitRewriter, ReturnKind, contains_impl_trait };
+use crate::helpers::{ Errors, hygienic_ident };
+use crate::paths::{ TracePaths, parse_option_key, skip_option_value };
+
+// `EqCore(l, r)` becomes `new_eq_core(l, r)`, the name of the constructor
+// `#[is_step]` generates for that variant.
+fn change_call_ident(e : &mut syn::Expr) -> Result<()> {
+    match e {
+        syn::Expr::Call(syn::ExprCall { func, .. }) => {
+            match func.as_mut() {
+                syn::Expr::Path(syn::ExprPath { path, .. }) => {
+                    // A parsed path always has at least one segment.
+                    let last_mut = path.segments.last_mut().expect("Failed to get last path segment in change_call_ident");
+                    let snakecase = crate::helpers::snake_case_name(&last_mut.ident);
+                    last_mut.ident = format_ident!("new_{}", snakecase);
+                    Ok(())
+                },
+                otherwise => Err(syn::Error::new_spanned(otherwise, "expected the name of a step variant, like the \
+                                                                    `EqCore` in `EqCore(l, r)`"))
+            }
+        },
+        otherwise => Err(syn::Error::new_spanned(otherwise, "expected the step to record to be written as a call \
+                                                            to a step variant, like `EqCore(l, r)`"))
+    }
+}
 
-// The acceptable forms of `Step` type annotation.
-fn type_is_step(type_ : &syn::Type) -> bool {
-    let form1 : syn::Type = parse_quote!(Step);
-    let form2 : syn::Type = parse_quote!(trace::Step);
-    let form3 : syn::Type = parse_quote!(crate::trace::Step);
-
-       (type_ == &form1)
-    || (type_ == &form2)
-    || (type_ == &form3)
+// Replaces each argument of the (already renamed) step constructor with a
+// hygienic local named `<prefix>_<n>`, and hands back the locals along
+// with the argument expressions they should be bound to. This lets the
+// arguments be evaluated before the manager is locked; an argument that
+// itself uses the tracer (say, a nested traced call) would otherwise
+// deadlock on the write lock.
+fn hoist_step_args(step : &mut syn::Expr, prefix : &str) -> Vec<(syn::Ident, syn::Expr)> {
+    match step {
+        syn::Expr::Call(syn::ExprCall { args, .. }) => {
+            args.iter_mut().enumerate().map(|(n, arg)| {
+                let arg_ident = hygienic_ident(&format!("{}_{}", prefix, n));
+                let arg_expr = std::mem::replace(arg, parse_quote!(#arg_ident));
+                (arg_ident, arg_expr)
+            }).collect()
+        },
+        // `change_call_ident` already rejected anything else.
+        _ => Vec::new()
+    }
+}
 
+// How the expansion gets a `&mut TraceMgr` out of the tracer location.
+#[derive(Clone, Copy, PartialEq)]
+enum Access {
+    // `RwLock<TraceMgr>` with a guard-returning `write()` (parking_lot style).
+    RwLock,
+    // `Mutex<TraceMgr>` with a guard-returning `lock()` (parking_lot style).
+    Mutex,
+    // `RefCell<TraceMgr>`.
+    RefCell,
+    // The location is a `&mut TraceMgr`.
+    Direct,
 }
 
-/*
-Safety check; ensure that either: 
-1. the `let this_step ...` local binding already has a type annotation of ` : Step `
-2. A type annotation of ` : Step` is added if it's not already there.
-*/
-fn step_declar_add_type(_stmt : &syn::Stmt) -> (syn::Ident, syn::Stmt) {
-    let full_step_type : syn::Type = parse_quote! {
-        crate::trace::Step
-    };
+impl Access {
+    fn parse_value(input : ParseStream) -> Result<Access> {
+        let value : syn::Ident = input.parse()?;
+        if value == "rwlock" {
+            Ok(Access::RwLock)
+        } else if value == "mutex" {
+            Ok(Access::Mutex)
+        } else if value == "refcell" {
+            Ok(Access::RefCell)
+        } else if value == "direct" {
+            Ok(Access::Direct)
+        } else {
+            Err(syn::Error::new(value.span(), "expected one of `rwlock`, `mutex`, `refcell` or `direct`"))
+        }
+    }
 
-    match _stmt {
-        syn::Stmt::Local(local) => {
-            let mut init_copy = local.init.as_ref().clone();
-
-            match &local.pat {
-                syn::Pat::Ident(syn::PatIdent { ident, .. }) => {
-                    let var_name = ident.clone();
-                    let init_val = local.init.clone();
-                    let mut new_stmt : syn::Stmt = parse_quote! {
-                        let #var_name : #full_step_type = panic!();
-                    };
-
-                    match new_stmt {
-                        syn::Stmt::Local(ref mut new_local) => {
-                            std::mem::replace(&mut new_local.attrs, local.attrs.clone());
-                            std::mem::replace(&mut new_local.init, local.init.clone());
-                            (var_name, new_stmt)
-
-                        },
-                        _ => panic!("nanoda_macros::step_declar_add_type, line {} : the variable `new_stmt` \
-                                     was not parsed as a syn::Stmt::Local. While not a big deal, this is \
-                                     probably not a user-serviceable
-                                     error, please report this to the author", line!())
-                    }
-                },
-                syn::Pat::Type(pat_type) => {
-                    if type_is_step(pat_type.ty.as_ref()) {
-                        let local_ident = match pat_type.pat.as_ref() {
-                            syn::Pat::Ident(pat_ident) => {
-                                pat_ident.ident.clone()
-                            },
-                            _ => panic!("Not a pat::Ident")
-                        };
-                        (local_ident, _stmt.clone())
-                    } else {
-                        panic!("tracing macro expected the first statement of the block to be a\
-                               local whose type is `Step`, but its type was {:#?}\n", pat_type.ty)
-                    }
-                },
-                _ => panic!("tracing macro expected the first statement of the block to be a\
-                              local of the form `let s : Step = mk_step()...` or `let s = mk_step()`")
-            }
-        },
-        _ => panic!("tracing macro expected the first statement of the block to be a\
-                     local (IE `let x : usize = 0;`, but instead it got some other type of statement!")
+    // The expression locking the manager. Locking strategies go through the
+    // `trace_mgr` binding made from the location; `direct` can't hold a
+    // borrow of the location across the body (the body needs it to trace
+    // nested calls) so it reborrows the location itself each time.
+    fn lock(self, trace_mgr : &syn::Ident, trace_mgr_loc : &syn::Expr) -> syn::Expr {
+        match self {
+            Access::RwLock => parse_quote! { #trace_mgr.write() },
+            Access::Mutex => parse_quote! { #trace_mgr.lock() },
+            Access::RefCell => parse_quote! { #trace_mgr.borrow_mut() },
+            Access::Direct => parse_quote! { &mut *(#trace_mgr_loc) },
+        }
     }
 }
 
+// How the traced function's result is recorded on its step.
+#[derive(Clone, Copy, PartialEq)]
+enum ResultMode {
+    // `result.clone()` is inserted; needs `R : Clone + HasInsertItem`.
+    Clone,
+    // `&result` is inserted; needs `&R : HasInsertItem`.
+    Ref,
+    // Nothing is inserted and the step's result stays `None`.
+    Skip,
+    // For `Result`/`Option` returns, a clone of the `Ok`/`Some` payload is
+    // inserted, and an `Err`/`None` marks the step as failed instead.
+    OkOnly,
+}
+
+// Where the body of a synchronous traced function runs.
+#[derive(Clone, Copy, PartialEq)]
+enum BodyMode {
+    // Inside a closure that is called immediately; `return` and `?`
+    // naturally stay inside it.
+    Closure,
+    // In place, inside a labeled block.
+    Block,
+}
+
+// The shapes of function `#[trace]` knows how to instrument.
+enum TracedBody {
+    // A plain `fn`; the body runs inside a closure.
+    Sync(syn::Block),
+    // An `async fn`; the body becomes a future that is polled in place.
+    AsyncFn(syn::Block),
+    // A `fn` whose whole body is an `async` block, like
+    // `fn f(..) -> impl Future<Output = T> { async move { .. } }`.
+    AsyncBlock(syn::ExprAsync),
+}
+
+impl TracedBody {
+    fn new(item_fn : &syn::ItemFn) -> Self {
+        if item_fn.sig.asyncness.is_some() {
+            return TracedBody::AsyncFn(item_fn.block.as_ref().clone())
+        }
 
-fn is_local_stmt(s : &Stmt) -> bool {
-    match s {
-        syn::Stmt::Local(..) => true,
-        _ => false
+        match item_fn.block.stmts.as_slice() {
+            [syn::Stmt::Expr(syn::Expr::Async(async_block))] => TracedBody::AsyncBlock(async_block.clone()),
+            _ => TracedBody::Sync(item_fn.block.as_ref().clone())
+        }
     }
 }
 
-fn change_call_ident(e : &mut syn::Expr) {
-    match e {
-        syn::Expr::Call(syn::ExprCall { func, .. }) => {
-            match func.as_mut() {
-                syn::Expr::Path(syn::ExprPath { path, .. }) => {
-                    let mut last_mut = path.segments.last_mut().expect("Failed to get last path segment in change_call_ident");
-                    let snakecase = crate::helpers::snake_case_name(&last_mut.ident);
-                    let new_snakecase = format_ident!("new_{}", snakecase);
-                    std::mem::replace(&mut last_mut.ident, new_snakecase);
-                },
-                _ => panic!("Expected Path in Expr::Call in change_call_ident")
+// For `impl Future<Output = T>` returns `T`; the result of a function
+// returning an `async` block is only annotated when it can be found.
+fn future_output_type(return_type : &syn::ReturnType) -> Option<syn::Type> {
+    let bounds = match return_type {
+        syn::ReturnType::Type(_, boxed_type) => match boxed_type.as_ref() {
+            syn::Type::ImplTrait(impl_trait) => &impl_trait.bounds,
+            _ => return None
+        },
+        syn::ReturnType::Default => return None
+    };
+
+    bounds.iter().find_map(|bound| match bound {
+        syn::TypeParamBound::Trait(trait_bound) => {
+            let last_segment = trait_bound.path.segments.last()?;
+            if last_segment.ident != "Future" {
+                return None
+            }
+            match &last_segment.arguments {
+                syn::PathArguments::AngleBracketed(args) => args.args.iter().find_map(|arg| match arg {
+                    syn::GenericArgument::Binding(binding) if binding.ident == "Output" => Some(binding.ty.clone()),
+                    _ => None
+                }),
+                _ => None
             }
         },
-        _ => panic!("Expected Expr::Call in change_call_ident")
-    }
+        _ => None
+    })
 }
 
 // Only needs to be mut so at the end we can swap the old
 // x.block.stmts with the new block stmts vec.
-fn add_tracing_to_item_fn(mut trace_attr : TraceAttr, mut item_fn : syn::ItemFn) -> syn::ItemFn {
-
-    let snake_cased = change_call_ident(&mut trace_attr.step);
-
-    let mut closure_block : syn::Block = item_fn.block.as_ref().clone();
-    trace_attr.visit_block_mut(&mut closure_block);
+fn add_tracing_to_item_fn(mut trace_attr : TraceAttr, mut item_fn : syn::ItemFn) -> Result<syn::ItemFn> {
+
+    // Every local the expansion introduces is hygienic, so it can't
+    // capture or shadow a binding of the same name in the traced body.
+    let trace_mgr = hygienic_ident("trace_mgr");
+    let trace_mgr_ptr = hygienic_ident("trace_mgr_ptr");
+    let this_step = hygienic_ident("this_step");
+    let this_step_idx = hygienic_ident("this_step_idx");
+    let stack_size_before = hygienic_ident("stack_size_before");
+    let safety_idx_before = hygienic_ident("safety_idx_before");
+    let result = hygienic_ident("result");
+    let result_idx = hygienic_ident("result_idx");
+    let write_guard = hygienic_ident("write_guard");
+    let recovered_this_step = hygienic_ident("recovered_this_step");
+    let unwind_guard = hygienic_ident("unwind_guard");
+    let traced_future = hygienic_ident("traced_future");
+    let poll = hygienic_ident("poll");
+    let cx = hygienic_ident("cx");
+    let payload = hygienic_ident("payload");
+    let value = hygienic_ident("value");
+    let on_err_args = hygienic_ident("on_err_args");
+    let failed_step = hygienic_ident("failed_step");
+    let failed_info = hygienic_ident("failed_info");
+    let recovered_info = hygienic_ident("recovered_info");
+
+    let mut push_extra_arg = PushExtraArg(parse_quote!(#safety_idx_before));
+    let mut body_shape = TracedBody::new(&item_fn);
+    match &mut body_shape {
+        TracedBody::Sync(block) | TracedBody::AsyncFn(block) => push_extra_arg.visit_block_mut(block),
+        TracedBody::AsyncBlock(async_block) => push_extra_arg.visit_block_mut(&mut async_block.block),
+    }
 
+    let step_arg_stmts = hoist_step_args(&mut trace_attr.step, "step_arg")
+                         .into_iter()
+                         .map(|(arg_ident, arg_expr)| parse_quote! { let #arg_ident = #arg_expr; })
+                         .collect::<Vec<syn::Stmt>>();
     let this_step_cnstr = &trace_attr.step;
     let trace_mgr_loc = &trace_attr.tracer_location;
-
-    let return_type : syn::Type = match (&item_fn.sig.output) {
-        syn::ReturnType::Default => parse_quote! { () },
-        syn::ReturnType::Type(_, boxed_type) => boxed_type.as_ref().clone()
+    let step_type = trace_attr.paths.step();
+    let has_insert_item = trace_attr.paths.has_insert_item();
+    let tracer = trace_attr.paths.tracer();
+
+    let return_type : Option<syn::Type> = match (&body_shape, &item_fn.sig.output) {
+        (TracedBody::AsyncBlock(..), output) => future_output_type(output),
+        (_, syn::ReturnType::Default) => Some(parse_quote! { () }),
+        (_, syn::ReturnType::Type(_, boxed_type)) => Some(boxed_type.as_ref().clone())
     };
+    // `let x : impl Trait = ..` isn't allowed, so those results go unannotated.
+    let result_annotation = return_type.as_ref()
+                            .filter(|t| !contains_impl_trait(t))
+                            .map(|t| quote! { : #t });
+    let return_kind = return_type.as_ref().map(ReturnKind::of).unwrap_or(ReturnKind::Other);
+
+    let access = trace_attr.access;
+    let lock = access.lock(&trace_mgr, trace_mgr_loc);
+
+    if access == Access::Direct {
+        if let TracedBody::AsyncFn(..) | TracedBody::AsyncBlock(..) = body_shape {
+            return Err(syn::Error::new_spanned(&item_fn.sig, "`access = direct` can't be used to trace async functions, \
+                                                              since the `&mut` manager would have to be held across awaits"))
+        }
+        if let (TracedBody::Sync(..), BodyMode::Block) = (&body_shape, trace_attr.body_mode) {
+            return Err(syn::Error::new_spanned(&item_fn.sig, "`access = direct` can't be combined with `body = block`, \
+                                                              since an unwinding body can't be caught without a closure"))
+        }
+    }
 
-    let step_declar = parse_quote! { let this_step : crate::trace::Step = (#trace_mgr_loc).write().#this_step_cnstr; };
-
-    let rest_as_closure : syn::ExprClosure = parse_quote!(|| #closure_block);
+    // The tracer location is evaluated before the body (to create and push
+    // the step) and again after it (to record the result and pop the step),
+    // taking the lock each time. Nothing borrowed from it is held while a
+    // synchronous body runs, so the body is free to use `&mut self`, or with
+    // `body = block` to move out of `self`'s other fields.
+    let bind_trace_mgr : Option<syn::Stmt> = if access != Access::Direct {
+        Some(parse_quote! { let #trace_mgr = &(#trace_mgr_loc); })
+    } else {
+        None
+    };
 
-    // instead of `#trace_mgr_loc`
-    // use what you parsed from the `attr` field,
-    // which tells you where you can find the mutable reference to trace_mgr.
     let mut new_block_stmts : Vec::<syn::Stmt> = vec![
         // Before closure/function body
-        parse_quote! { use crate::trace::HasInsertItem; },
-        parse_quote! { use crate::trace::Tracer; },
+        parse_quote! { use #has_insert_item as _; },
+        parse_quote! { use #tracer as _; },
+    ];
+
+    new_block_stmts.extend(step_arg_stmts);
+
+    new_block_stmts.extend(vec![
+        parse_quote! {
+            let (#stack_size_before, #safety_idx_before) = {
+                #bind_trace_mgr
+                let mut #write_guard = #lock;
+                let #this_step : #step_type = #write_guard.#this_step_cnstr;
+                let #stack_size_before = #write_guard.stack_len();
+                let #safety_idx_before = *(#this_step.get_safety_idx());
+                #write_guard.push(#this_step);
+                (#stack_size_before, #safety_idx_before)
+            };
+        },
+    ]);
 
-        step_declar,
+    // The body sees the safety index only under the name it asked for.
+    if let Some(user_ident) = &trace_attr.safety_idx {
+        new_block_stmts.push(parse_quote! { let #user_ident = #safety_idx_before; });
+    }
 
-        parse_quote! { let stack_size_before = (#trace_mgr_loc).read().stack_len() ; },
-        parse_quote! { let ___safety_idx_before = *(this_step.get_safety_idx()); },
-        parse_quote! { (#trace_mgr_loc).write().push(this_step); },
+    // If the body unwinds (the checker uses panics for rejected
+    // declarations and catches them further up), the step is popped,
+    // marked as aborted and still handed to the tracer, so the manager's
+    // stack is the same size as before the call.
+    let abort_block : syn::Block = parse_quote! {{
+        let mut #write_guard = #lock;
+        let mut #recovered_this_step = #write_guard.pop();
+        let #this_step_idx = #write_guard.next_step_idx();
+        *#recovered_this_step.get_mut_self_idx() = Some(#this_step_idx);
+        #write_guard.add_child(#this_step_idx, &#recovered_this_step);
+        #recovered_this_step.mark_aborted();
+        #write_guard.trace_step(&#recovered_this_step);
+    }};
+
+    // The guard type is local to its block so the body can't see it.
+    // `rebind_trace_mgr` lets a guard reach the manager through something
+    // other than a borrow of the tracer location.
+    let mk_unwind_guard = |rebind_trace_mgr : Option<syn::Stmt>| -> syn::Stmt {
+        parse_quote! {
+            let #unwind_guard = {
+                struct UnwindGuard<F : FnMut()>(F);
+
+                impl<F : FnMut()> Drop for UnwindGuard<F> {
+                    fn drop(&mut self) {
+                        (self.0)()
+                    }
+                }
 
-        // Closure + closure.call()
-        parse_quote! { let result____ : #return_type = { #rest_as_closure }(); },
-        
-        // After closure :
-        parse_quote! { let mut write_guard = (#trace_mgr_loc).write(); },
+                UnwindGuard(|| {
+                    #rebind_trace_mgr
+                    #abort_block
+                })
+            };
+        }
+    };
 
-        parse_quote! { let result_idx = result____.clone().insert_item(&mut (*write_guard).item_storage); },
-        parse_quote! { let mut recovered_this_step = write_guard.pop(); },
-        parse_quote! { let this_step_idx = write_guard.next_step_idx(); },
+    // A synchronous body's guard only holds a pointer to the tracer location,
+    // which doesn't borrow it. The location outlives the guard, since `self`
+    // and the other arguments are dropped after the function's locals, and a
+    // body that moves the location away on a path that completes doesn't
+    // compile, since the location is evaluated again after the body. What's
+    // left is a body that moves the location away and then panics, which the
+    // docs rule out.
+    let sync_unwind_guard_stmts : Vec<syn::Stmt> = vec![
+        parse_quote! { let #trace_mgr_ptr : *const _ = &(#trace_mgr_loc); },
+        mk_unwind_guard(Some(parse_quote! { let #trace_mgr = unsafe { &*#trace_mgr_ptr }; })),
+    ];
+
+    match &body_shape {
+        // A guard would have to hold the `&mut` manager across the body,
+        // so with `direct` access the unwind is caught, recorded and resumed.
+        TracedBody::Sync(block) if access == Access::Direct => {
+            let rest_as_closure : syn::ExprClosure = parse_quote!(|| #block);
+            new_block_stmts.push(parse_quote! {
+                let #result #result_annotation = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(#rest_as_closure)) {
+                    Ok(#result) => #result,
+                    Err(#payload) => {
+                        #abort_block
+                        std::panic::resume_unwind(#payload)
+                    }
+                };
+            });
+        },
+        // The body stays in place in a labeled block; its early exits
+        // are rewritten to break out of the block instead of the function.
+        TracedBody::Sync(block) if trace_attr.body_mode == BodyMode::Block => {
+            let mut block = block.clone();
+            let mut rewriter = EarlyExitRewriter::new(return_kind);
+            rewriter.visit_block_mut(&mut block);
+            rewriter.errors.finish()?;
+            let label = rewriter.label;
+            new_block_stmts.extend(sync_unwind_guard_stmts);
+            new_block_stmts.extend(vec![
+                parse_quote! { let #result #result_annotation = #label : #block; },
+                // The body ran to completion; disarm the guard.
+                parse_quote! { std::mem::forget(#unwind_guard); },
+            ]);
+        },
+        TracedBody::Sync(block) => {
+            let rest_as_closure : syn::ExprClosure = parse_quote!(|| #block);
+            new_block_stmts.extend(sync_unwind_guard_stmts);
+            new_block_stmts.extend(vec![
+                // Closure + closure.call()
+                parse_quote! { let #result #result_annotation = { #rest_as_closure }(); },
+                // The body returned normally; disarm the guard.
+                parse_quote! { std::mem::forget(#unwind_guard); },
+            ]);
+        },
+        TracedBody::AsyncFn(block) | TracedBody::AsyncBlock(syn::ExprAsync { block, .. }) => {
+            // The step is only on the manager's stack while this future is
+            // being polled. Between polls it is taken back off, so other
+            // tasks sharing the manager never see it as their parent; on
+            // completion it is left on the stack for the usual epilogue.
+            // The prologue already pushed it for the first poll. The tracer
+            // location stays borrowed until the future completes.
+            let unwind_guard_stmt = mk_unwind_guard(None);
+            new_block_stmts.extend(bind_trace_mgr.clone());
+            new_block_stmts.extend(vec![
+                parse_quote! { let mut #this_step : Option<#step_type> = None; },
+                parse_quote! { let mut #traced_future = Box::pin(async #block); },
+                parse_quote! {
+                    let #result #result_annotation = std::future::poll_fn(|#cx| {
+                        if let Some(#this_step) = #this_step.take() {
+                            #lock.push(#this_step);
+                        }
+                        #unwind_guard_stmt
+                        let #poll = std::future::Future::poll(#traced_future.as_mut(), #cx);
+                        std::mem::forget(#unwind_guard);
+                        if #poll.is_pending() {
+                            #this_step = Some(#lock.pop());
+                        }
+                        #poll
+                    }).await;
+                },
+            ]);
+        },
+    }
+
+    // With `result = ok_only` or `on_err = ..`, only the `Ok`/`Some` payload
+    // is recorded as the result, and an `Err`/`None` marks the step as failed
+    // (swapping in the `on_err` step, if there is one).
+    let split_outcome = trace_attr.result_mode == ResultMode::OkOnly || trace_attr.on_err.is_some();
+    let (ok_pat, fail_pat) : (syn::Pat, syn::Pat) = match return_kind {
+        ReturnKind::Result => (parse_quote!(Ok(#value)), parse_quote!(Err(_))),
+        ReturnKind::Option => (parse_quote!(Some(#value)), parse_quote!(None)),
+        ReturnKind::Other if split_outcome => {
+            return Err(syn::Error::new_spanned(&item_fn.sig.output, "`result = ok_only` and `on_err` need the function \
+                                                                     to return an `Option` or a `..Result` type"))
+        },
+        ReturnKind::Other => (parse_quote!(_), parse_quote!(_)),
+    };
+
+    // How the result gets into the item storage, if at all.
+    let storage = quote! { &mut (*#write_guard).item_storage };
+    let result_item : Option<syn::Expr> = match (split_outcome, trace_attr.result_mode) {
+        (_, ResultMode::Skip) => None,
+        (false, ResultMode::Ref) => Some(parse_quote! { Some(#has_insert_item::insert_item(&#result, #storage)) }),
+        (false, _) => Some(parse_quote! { Some(#result.clone().insert_item(#storage)) }),
+        (true, ResultMode::Ref) => Some(parse_quote! {
+            match &#result {
+                #ok_pat => Some(#has_insert_item::insert_item(#value, #storage)),
+                _ => None
+            }
+        }),
+        (true, _) => Some(parse_quote! {
+            match &#result {
+                #ok_pat => Some(#value.clone().insert_item(#storage)),
+                _ => None
+            }
+        }),
+    };
+
+    // The `on_err` step's arguments are only evaluated on failure, and like
+    // the step's own, before the manager is locked.
+    let on_err = trace_attr.on_err.clone().map(|mut on_err_cnstr| {
+        let (arg_idents, arg_exprs) : (Vec<syn::Ident>, Vec<syn::Expr>) = hoist_step_args(&mut on_err_cnstr, "on_err_arg").into_iter().unzip();
+        new_block_stmts.push(parse_quote! {
+            let #on_err_args = match &#result {
+                #fail_pat => Some((#(#arg_exprs,)*)),
+                _ => None
+            };
+        });
+        (on_err_cnstr, arg_idents)
+    });
+
+    // After closure :
+    new_block_stmts.extend(bind_trace_mgr);
+    new_block_stmts.push(parse_quote! { let mut #write_guard = #lock; });
+
+    if let Some(result_item) = &result_item {
+        new_block_stmts.push(parse_quote! { let #result_idx = #result_item; });
+    }
+
+    new_block_stmts.push(parse_quote! { let mut #recovered_this_step = #write_guard.pop(); });
+
+    // The replacement step takes over the original's info, so its safety
+    // index, children and extras carry over.
+    if let Some((on_err_cnstr, arg_idents)) = on_err {
+        new_block_stmts.push(parse_quote! {
+            if let Some((#(#arg_idents,)*)) = #on_err_args {
+                let mut #failed_step : #step_type = #write_guard.#on_err_cnstr;
+                if let (Some(#failed_info), Some(#recovered_info)) = (#failed_step.get_mut_step_info(), #recovered_this_step.get_mut_step_info()) {
+                    std::mem::swap(#failed_info, #recovered_info);
+                }
+                #recovered_this_step = #failed_step;
+            }
+        });
+    }
+
+    if split_outcome {
+        new_block_stmts.push(parse_quote! {
+            if let #fail_pat = &#result {
+                #recovered_this_step.mark_failed();
+            }
+        });
+    }
+
+    new_block_stmts.extend(vec![
+        parse_quote! { let #this_step_idx = #write_guard.next_step_idx(); },
 
         // Assert that current step's `self_idx` was uninitialized/None
         // Then replace with the generated index.
-        parse_quote! { assert!(recovered_this_step.get_self_idx().is_none()); },
-        parse_quote! { std::mem::replace(recovered_this_step.get_mut_self_idx(), Some(this_step_idx)); },
+        parse_quote! { assert!(#recovered_this_step.get_self_idx().is_none()); },
+        parse_quote! { *#recovered_this_step.get_mut_self_idx() = Some(#this_step_idx); },
 
         // Add this steps' index to it's parent's list of child steps
-        parse_quote! { write_guard.add_child(this_step_idx, &recovered_this_step); },
+        parse_quote! { #write_guard.add_child(#this_step_idx, &#recovered_this_step); },
 
         // Asser that the current step's result was uninitialized/None
         // then initialize it.
-        parse_quote! { assert!(recovered_this_step.get_result().is_none()); },
-        parse_quote! { std::mem::replace(recovered_this_step.get_mut_result(), Some(result_idx)); },
+        parse_quote! { assert!(#recovered_this_step.get_result().is_none()); },
+    ]);
 
+    if result_item.is_some() {
+        new_block_stmts.push(parse_quote! { *#recovered_this_step.get_mut_result() = #result_idx; });
+    }
+
+    new_block_stmts.extend(vec![
         // Execute the trace() function on this step before dropping it.
-        parse_quote! { write_guard.trace_step(&recovered_this_step); },
+        parse_quote! { #write_guard.trace_step(&#recovered_this_step); },
 
         // Assert sanity check invariants
-        parse_quote! { assert_eq!(stack_size_before, write_guard.stack_len()); },
-        parse_quote! { assert_eq!(___safety_idx_before, *(recovered_this_step.get_safety_idx())); },
-   ];
+        parse_quote! { assert_eq!(#stack_size_before, #write_guard.stack_len()); },
+        parse_quote! { assert_eq!(#safety_idx_before, *(#recovered_this_step.get_safety_idx())); },
+    ]);
 
     // push final return statement; parse_quote doesn't want to do this as
     // a `syn::Stmt::Expr`; complains about no semicolon.
-    new_block_stmts.push(syn::Stmt::Expr(parse_quote! { result____ }));
-
-    std::mem::replace(&mut item_fn.block.stmts, new_block_stmts);
-    item_fn
+    new_block_stmts.push(syn::Stmt::Expr(parse_quote! { #result }));
+
+    item_fn.block.stmts = match body_shape {
+        TracedBody::AsyncBlock(syn::ExprAsync { attrs, capture, .. }) => {
+            // Everything runs inside the returned future, so the step
+            // is still only created and pushed on first poll.
+            vec![syn::Stmt::Expr(parse_quote! {
+                #(#attrs)*
+                async #capture {
+                    #(#new_block_stmts)*
+                }
+            })]
+        },
+        _ => new_block_stmts
+    };
+    Ok(item_fn)
 }
 
 
 
 
 
+#[derive(Clone)]
 struct TraceAttr {
     pub tracer_location : syn::Expr,
     pub step : syn::Expr,
+    pub paths : TracePaths,
+    // `cfg = <predicate>`; when present, the traced function is only
+    // emitted when the predicate holds, and the untouched one otherwise.
+    pub cfg : Option<syn::NestedMeta>,
+    // `safety_idx = name`; binds the step's safety index to `name` in
+    // the body, for code that needs it beyond `push_extra` calls.
+    pub safety_idx : Option<syn::Ident>,
+    // `safety_idx_type = Type`; the type `safety_idx` is bound with when
+    // the function isn't traced. Defaults to `<trace_mod>::SafetyIdx`.
+    pub safety_idx_type : Option<syn::Type>,
+    // `access = ..`; how to get at the manager behind the tracer location.
+    pub access : Access,
+    // `body = closure | block`
+    pub body_mode : BodyMode,
+    // `result = clone | ref | skip | ok_only`
+    pub result_mode : ResultMode,
+    // `on_err = StepVariant(args..)`; already renamed to its constructor.
+    pub on_err : Option<syn::Expr>,
 }
 
 impl TraceAttr {
     pub fn new(tracer_location : syn::Expr, step : syn::Expr) -> Self {
         TraceAttr {
             tracer_location,
-            step
+            step,
+            paths : TracePaths::default(),
+            cfg : None,
+            safety_idx : None,
+            safety_idx_type : None,
+            access : Access::RwLock,
+            body_mode : BodyMode::Closure,
+            result_mode : ResultMode::Clone,
+            on_err : None,
+        }
+    }
+
+    // Same contract as `TracePaths::parse_option`.
+    fn parse_option(&mut self, key : &syn::Ident, input : ParseStream) -> Result<bool> {
+        if key == "cfg" {
+            self.cfg = Some(input.parse()?);
+            Ok(true)
+        } else if key == "safety_idx" {
+            self.safety_idx = Some(input.parse()?);
+            Ok(true)
+        } else if key == "safety_idx_type" {
+            self.safety_idx_type = Some(input.parse()?);
+            Ok(true)
+        } else if key == "access" {
+            self.access = Access::parse_value(input)?;
+            Ok(true)
+        } else if key == "body" {
+            let value : syn::Ident = input.parse()?;
+            self.body_mode = if value == "closure" {
+                BodyMode::Closure
+            } else if value == "block" {
+                BodyMode::Block
+            } else {
+                return Err(syn::Error::new(value.span(), "expected `closure` or `block`"))
+            };
+            Ok(true)
+        } else if key == "result" {
+            // `ref` is a keyword, so the value is parsed like the keys are.
+            let value = input.call(syn::Ident::parse_any)?;
+            self.result_mode = if value == "clone" {
+                ResultMode::Clone
+            } else if value == "ref" {
+                ResultMode::Ref
+            } else if value == "skip" {
+                ResultMode::Skip
+            } else if value == "ok_only" {
+                ResultMode::OkOnly
+            } else {
+                return Err(syn::Error::new(value.span(), "expected `clone`, `ref`, `skip` or `ok_only`"))
+            };
+            Ok(true)
+        } else if key == "on_err" {
+            let mut on_err_step = input.parse::<syn::Expr>()?;
+            change_call_ident(&mut on_err_step)?;
+            self.on_err = Some(on_err_step);
+            Ok(true)
+        } else {
+            self.paths.parse_option(key, input)
         }
     }
     
 }
 
-impl VisitMut for TraceAttr {
-    fn visit_expr_method_call_mut(&mut self, x : &mut syn::ExprMethodCall) {
-        let target_ident = format_ident!("push_extra");
-
-        let new_arg : syn::Expr = parse_quote!(___safety_idx_before);
+// Appends `.0` to every `push_extra(..)` call it visits. The traced
+// expansion appends its own hygienic safety index binding, so it can't be
+// confused with anything the body declares; untraced ones append a
+// `Default::default()` placeholder so the calls still type check.
+struct PushExtraArg(syn::Expr);
 
-        if &x.method == &target_ident {
-            x.args.push(new_arg)
+impl VisitMut for PushExtraArg {
+    fn visit_expr_method_call_mut(&mut self, x : &mut syn::ExprMethodCall) {
+        syn::visit_mut::visit_expr_method_call_mut(self, x);
+        if x.method == "push_extra" {
+            x.args.push(self.0.clone())
         }
     }
 }
 
+// `push_extra(extra)` becomes `push_extra(extra, Default::default())`.
+fn fill_push_extra_placeholders(item_fn : &mut syn::ItemFn) {
+    PushExtraArg(parse_quote!(Default::default())).visit_block_mut(&mut item_fn.block)
+}
+
 // Expr::Field, Expr::Call
 // or
 // Expr::Reference, Expr::Call
+// followed by any number of `key = value` options.
 impl Parse for TraceAttr {
     fn parse(input : ParseStream) -> Result<TraceAttr> {
-        use syn::punctuated::Punctuated;
         use syn::token::Comma;
 
-        let mut parsed = match Punctuated::<syn::Expr, Comma>::parse_terminated(input) {
-            Ok(p) => p.into_iter(),
-            Err(e) => panic!("Failed to parse Trace Attribute as #[trace(trace_loc, step)]. Error : {}", e)
-        };
-        match (parsed.next(), parsed.next()) {
-            (Some(fst), Some(snd)) => Ok(TraceAttr::new(fst, snd)),
-            (Some(fst), None) => panic!("trace attribute macro needs to know what step to record as its second argument!"),
-            (None, Some(snd)) => panic!("trace attribute macro needs a trace_mgr location as its first argument"),
-            _ => panic!("trace attribute macro needs two arguments; a trace_mgr location, and a step. got neither.")
+        if input.is_empty() {
+            return Err(input.error("trace attribute macro needs two arguments; a trace_mgr location, and a step. got neither."))
+        }
+
+        let fst = input.parse::<syn::Expr>()?;
+
+        if input.parse::<Option<Comma>>()?.is_none() || input.is_empty() {
+            return Err(syn::Error::new_spanned(&fst, "trace attribute macro needs to know what step to record as its second argument!"))
+        }
+
+        let snd = input.parse::<syn::Expr>()?;
+
+        let mut trace_attr = TraceAttr::new(fst, snd);
+        let mut errors = Errors::default();
+        // Rename the step to its constructor here, so a malformed step is
+        // reported alongside any malformed options.
+        errors.take(change_call_ident(&mut trace_attr.step));
+
+        while input.parse::<Option<Comma>>()?.is_some() && !input.is_empty() {
+            let key = parse_option_key(input)?;
+            if key == "mgr_type" || key == "info_type" {
+                errors.push(syn::Error::new(key.span(), "`mgr_type`/`info_type` only apply to `#[is_step]`"));
+                skip_option_value(input)?;
+            } else if !trace_attr.parse_option(&key, input)? {
+                errors.push(syn::Error::new(key.span(), format!("unknown option `{}` in trace attribute", key)));
+                skip_option_value(input)?;
+            }
+        }
+
+        if !input.is_empty() {
+            errors.push(input.error("expected `,` between the arguments to the trace attribute"));
         }
+
+        errors.finish()?;
+        Ok(trace_attr)
    }
 }
 
 
+// The function as emitted when it isn't traced. A `safety_idx = name` binding
+// is still made, and `push_extra` calls still get a safety index argument,
+// both `Default::default()`, so the body keeps compiling. The binding is
+// annotated with the safety index type, since the body needn't pin it down.
+fn untraced_item_fn(trace_attr : &TraceAttr, mut item_fn : syn::ItemFn) -> syn::ItemFn {
+    fill_push_extra_placeholders(&mut item_fn);
+    if let Some(user_ident) = &trace_attr.safety_idx {
+        let safety_idx_type = match &trace_attr.safety_idx_type {
+            Some(safety_idx_type) => safety_idx_type.clone(),
+            None => {
+                let safety_idx_path = trace_attr.paths.safety_idx();
+                parse_quote!(#safety_idx_path)
+            }
+        };
+        item_fn.block.stmts.insert(0, parse_quote! {
+            #[allow(unused_variables)]
+            let #user_ident : #safety_idx_type = Default::default();
+        });
+    }
+    item_fn
+}
+
+fn expand_trace(trace_attr : TraceAttr, item_fn : syn::ItemFn) -> Result<proc_macro2::TokenStream> {
+    // With the `no-trace` feature the function is emitted untraced.
+    if cfg!(feature = "no-trace") {
+        let untraced = untraced_item_fn(&trace_attr, item_fn);
+        return Ok(quote! { #untraced })
+    }
+
+    match trace_attr.cfg.clone() {
+        Some(predicate) => {
+            let untraced = untraced_item_fn(&trace_attr, item_fn.clone());
+            let traced = add_tracing_to_item_fn(trace_attr, item_fn)?;
+            Ok(quote! {
+                #[cfg(#predicate)]
+                #traced
+                #[cfg(not(#predicate))]
+                #untraced
+            })
+        },
+        None => {
+            let traced = add_tracing_to_item_fn(trace_attr, item_fn)?;
+            Ok(quote! { #traced })
+        }
+    }
+}
+
+/// `#[trace(trace_mgr_location, StepVariant(args..), options..)]`
+///
+/// Options :
+/// - `crate = path` : the crate whose `trace` module holds the tracer; defaults to `crate`.
+/// - `trace_mod = path` : the tracer module itself; defaults to `crate::trace`.
+/// - `step_type = path` : override the step type annotated on the recorded step.
+///   (`mgr_type` and `info_type` are `#[is_step]` options and rejected here.)
+/// - `access = rwlock | mutex | refcell | direct` : how the tracer location
+///   holds the manager. `rwlock` (the default) and `mutex` expect locks whose
+///   `write()`/`lock()` return the guard directly, as parking_lot's do;
+///   `direct` expects the location to be a `&mut TraceMgr`, and re-evaluates
+///   it before and after the body instead of holding on to it.
+/// - `body = closure | block` : by default the body of a synchronous function
+///   runs in a closure. With `block` it stays in place inside a labeled block,
+///   with `return`s turned into `break`s and `?` turned into a match (for
+///   functions returning `Option` or a `..Result` type). This keeps `#[inline]`
+///   and borrow checking of the body as written, so a method taking `self` by
+///   value can move its other fields out; an early exit hidden inside a macro
+///   invocation skips the epilogue and is recorded as aborted.
+/// - `result = clone | ref | skip | ok_only` : how the return value is
+///   recorded. By default a clone of it is inserted into the item storage;
+///   `ref` inserts `&R` instead (which needs `impl HasInsertItem for &R`), and
+///   `skip` leaves the step without a result. For functions returning `Option`
+///   or a `..Result` type, `ok_only` records only the `Some`/`Ok` payload, and
+///   marks the step with `Step::mark_failed` on `None`/`Err`.
+/// - `on_err = StepVariant(args..)` : like `ok_only`, but a failed step is also
+///   replaced by the given variant, which takes over the original's `StepInfo`
+///   (through the `get_mut_step_info` accessor `#[is_step]` generates). Its
+///   arguments are only evaluated on failure, after the body has run. With
+///   `result = ref` the payload is recorded by reference.
+/// - `safety_idx = name` : bind the step's safety index to `name` in the body.
+///   Calls to `push_extra(..)` in the body get it appended automatically;
+///   the expansion's other locals are hygienic and not visible to the body.
+///   When the function isn't traced (`cfg`, `no-trace`) `name` is bound to
+///   `Default::default()` instead, as a `SafetyIdx` from the tracer module
+///   (a type alias for whatever `get_safety_idx` returns a reference to).
+/// - `safety_idx_type = Type` : the type `safety_idx` is bound with when the
+///   function isn't traced, if the tracer module has no `SafetyIdx` alias.
+/// - `cfg = predicate` : only trace the function when `#[cfg(predicate)]` holds,
+///   ie `cfg = feature = "trace"`; otherwise it is emitted untouched.
+///
+/// Building this crate with the `no-trace` feature turns every `#[trace]`
+/// into a no-op. An untraced function's `push_extra(..)` calls still get a
+/// safety index appended, `Default::default()`, so they keep the arity of
+/// the traced ones.
+///
+/// `async fn`s, and `fn`s whose body is a single `async` block, are traced
+/// as futures : the step is created and pushed on first poll, taken off the
+/// manager's stack whenever the body is pending, and finalized when it
+/// completes.
+///
+/// The tracer location is evaluated, and its lock taken, once before the body
+/// and once after it. Nothing borrowed from it is held while the body of a
+/// synchronous function runs, so the body can take `&mut self`; a body that
+/// unwinds reaches the manager through a pointer to the location instead.
+/// The body mustn't move the location away on a path that panics. `self`
+/// consuming methods need `body = block`, since the closure would take all
+/// of `self`. An `async` function keeps the location borrowed until its
+/// future completes.
+///
+/// If the function body unwinds, the step is still popped off the manager's
+/// stack, marked with `Step::mark_aborted` and passed to `trace_step`.
 #[proc_macro_attribute]
 pub fn trace(_attr : TokenStream, input : TokenStream) -> TokenStream {
-    let attr_contents = parse_macro_input!(_attr as TraceAttr);
     let original_function = parse_macro_input!(input as syn::ItemFn);
-    let new_token_stream = add_tracing_to_item_fn(attr_contents, original_function);
+    let attr_contents = syn::parse::<TraceAttr>(_attr);
+    let traced = match &attr_contents {
+        Ok(attr_contents) => expand_trace(attr_contents.clone(), original_function.clone()),
+        Err(e) => Err(e.clone())
+    };
 
-    TokenStream::from(quote! {
-        #new_token_stream
-    })
+    match traced {
+        Ok(new_token_stream) => TokenStream::from(new_token_stream),
+        // Still emit the function, untraced, so the error above is the only
+        // one reported rather than every use of the function failing too.
+        // Its `push_extra` calls and `safety_idx` binding are filled in the
+        // same way as for an untraced expansion.
+        Err(e) => {
+            let compile_error = e.to_compile_error();
+            let untraced = match &attr_contents {
+                Ok(attr_contents) => untraced_item_fn(attr_contents, original_function),
+                Err(..) => {
+                    let mut untraced = original_function;
+                    fill_push_extra_placeholders(&mut untraced);
+                    untraced
+                }
+            };
+            TokenStream::from(quote! {
+                #compile_error
+                #untraced
+            })
+        }
+    }
 }
 
 
 
 
+/// `#[is_step(options..)]` on the step enum.
+///
+/// Options :
+/// - `crate = path`, `trace_mod = path` : as for `#[trace]`.
+/// - `mgr_type = path`, `info_type = path` : override the manager the
+///   constructors are added to and the info type they construct.
+/// - `common(field, ..)` : fields shared by the variants, which the
+///   constructors don't take; a field can also be marked `#[common]`.
+///   Common fields other than `info` are set to `Default::default()`.
+///   Fields are matched across variants by name, and same-named fields
+///   must have the same type.
+/// - `explicit_ids` : every variant must be given an id with `#[step_id = N]`.
+/// - `codec` : generate the binary encoding described below.
+///   `codec(tests)` also generates a `#[cfg(test)]` round-trip test per
+///   variant, built from `Default` fields.
+/// - `json` : generate the JSON Lines writer and reader described below.
+///
+/// A field marked `#[item(Type)]`, like `#[item(Expr)] l : ExprIdx`, makes
+/// its constructor argument a `Type`, so passing the wrong kind of item is a
+/// compile error; other arguments are `impl HasInsertItem`.
+///
+/// Variants can be struct-like, tuple or unit variants. A struct-like
+/// variant keeps its `StepInfo` in its `info` field; a tuple variant in the
+/// field marked `#[info]`, or else the field whose type is the info type.
+/// Tuple variants' constructors take their other fields positionally.
+///
+/// Generates `get_step_name_string`, `get_step_name_string_short`,
+/// `get_step_info`/`get_mut_step_info` and a `TraceMgr::new_<variant>`
+/// constructor for each variant. Everything is generated for the annotated
+/// enum itself, generics and where-clause included, so a crate can have more
+/// than one step enum; a generic enum's generics go on each constructor.
+/// Enums whose constructors go on the same manager type need distinct
+/// variant names.
+///
+/// Also generates a fieldless `StepKind` enum (named `<Enum>Kind`) with a
+/// variant per step variant, `Step::kind()`, `StepKind::ALL`,
+/// `StepKind::name()`/`short_name()`, and `FromStr` accepting either name.
+/// Kinds are `Copy`, `Hash` and `Ord`, so they can key maps and counters.
+///
+/// Each variant has a numeric id, `Step::id() -> u16`, with
+/// `StepKind::id()`/`StepKind::from_id(u16)`, for binary trace formats.
+/// `#[step_id = N]` on a variant fixes its id; variants without one get the
+/// lowest ids that aren't taken, in declaration order, so only fixed ids are
+/// stable across reordering. Duplicate ids are an error.
+///
+/// Also generates `StepVisitor` and `StepVisitorMut` traits (named
+/// `<Enum>Visitor`/`<Enum>VisitorMut`), with a `visit_<snake_name>` method per
+/// variant that does nothing by default. Each method takes the variant's info
+/// first (if it has one), then its other fields, by reference. `Step::accept`
+/// and `Step::accept_mut` call the method for the step's variant.
+///
+/// Also generates a `StepChecker` trait (named `<Enum>Checker`) for replaying
+/// a finished trace, with a required `check_<snake_name>` method per variant
+/// taking the items, the step's info and fields, and its result item (from
+/// the checker's `result_item`). Fields marked `#[item(T)]` are passed
+/// resolved, as `&T`, through an `Index<FieldIdx, Output = T>` bound on the
+/// checker's `Items`. `Step::check_trace(steps, items, checker)` checks the
+/// steps in the order they were traced, children first, and returns a
+/// `StepCheckFailure` with the index, kind and short name of the first step
+/// that fails.
+///
+/// With `codec`, generates `Step::encode(&self, &mut impl Write)` and
+/// `Step::decode(&mut impl Read)`, a compact binary format writing the step's
+/// id as a varint followed by each field in declaration order. Fields are
+/// written through a generated `StepCodec` trait (named `<Enum>Codec`),
+/// implemented for unsigned integers (as varints), `bool`, `Option` and `Vec`;
+/// the index types and `StepInfo` need implementations of it.
+///
+/// With `json`, generates `Step::write_json(&self, &mut impl Write)`, writing
+/// the step as one line of JSON, `{"kind":"EqCore","short":"EC","fields":{"l":12,"r":40},..}`,
+/// with the entries of the step's info object (like `"idx"`, `"result"` and
+/// `"children"`) after `"fields"`. Tuple fields are keyed by position.
+/// `Step::read_json(&str)` and `Step::read_json_lines(impl BufRead)` read
+/// steps back, accepting long or short kind names. Values go through a
+/// generated `StepJson` trait (named `<Enum>Json`), implemented for unsigned
+/// integers, `bool`, `String`, `Option` and `Vec`, and a generated
+/// `StepJsonValue` type with its own parser, so no JSON crate is needed.
+///
+/// `#[step_display("{l} ≡ {r} by core defeq")]` on a variant gives its
+/// `Display` output, and `#[step_explain("..")]` a longer `Step::explain()`.
+/// Placeholders name the variant's fields (`{0}` for tuple fields) and can
+/// take a format spec, like `{l:?}`; naming a field the variant doesn't have
+/// is an error. Variants without a template display as their name, and
+/// explain as their display. `Display` is only generated if some variant
+/// has a template.
+///
+/// Only the macro's own helper attributes (`#[short]`, `#[step_id]`,
+/// `#[step_display]`, `#[step_explain]`, `#[info]`, `#[common]`, `#[item]`)
+/// are removed from the enum. Derives and attribute macros applied after
+/// `#[is_step]` don't see them, so ones whose helper attributes share these
+/// names, say an `#[item]` of their own, can't be combined with it. A variant's `#[cfg]` is copied onto its
+/// constructor and match arms, so feature-gated steps compile either way.
 #[proc_macro_attribute]
 pub fn is_step(_attr : TokenStream, input : TokenStream) -> TokenStream {
 
     let mut as_enum = parse_macro_input!(input as syn::ItemEnum);
+    let mut errors = Errors::default();
+
+    let step_attr = match errors.take(crate::step_derive::StepAttr::parse.parse(_attr)) {
+        Some((step_attr, attr_errors)) => {
+            errors.take(attr_errors.finish());
+            step_attr
+        },
+        None => crate::step_derive::StepAttr::default()
+    };
+    let paths = &step_attr.paths;
 
     // Collect the set of "short" names to use
-    let short_set = crate::step_derive::collect_short_attrs(&mut as_enum);
-    // Generate function to output short names for printing
-    let short_name_getters = crate::step_derive::mk_name_getters_short(short_set);
-    let name_getters = crate::step_derive::mk_name_getters2(&as_enum);
-    let cnstr_impls = crate::step_derive::derive_cnstrs2(&as_enum);
-
-    TokenStream::from(quote! {
-        #as_enum
-        #short_name_getters
-        #name_getters
-        #(#cnstr_impls)*
-    })
+    let short_set = errors.take(crate::step_derive::collect_short_attrs(&mut as_enum));
+    let step_ids = errors.take(crate::step_kind::collect_step_ids(&mut as_enum, step_attr.explicit_ids));
+    let templates = errors.take(crate::step_display::collect_templates(&mut as_enum));
+    let cnstr_impls = errors.take(crate::step_derive::derive_cnstrs2(&as_enum, &step_attr));
+    let step_checker = errors.take(crate::step_checker::mk_step_checker(&as_enum, paths));
+    crate::step_derive::strip_field_attrs(&mut as_enum);
+
+    match (errors.finish(), short_set, step_ids, templates, cnstr_impls, step_checker) {
+        (Ok(()), Some(short_set), Some(step_ids), Some(templates), Some(cnstr_impls), Some(step_checker)) => {
+            // Generate function to output short names for printing
+            let short_name_getters = crate::step_derive::mk_name_getters_short(&short_set, &as_enum);
+            let name_getters = crate::step_derive::mk_name_getters2(&as_enum);
+            let info_getters = crate::step_derive::mk_info_getters(&as_enum, paths);
+            let step_kind = crate::step_kind::mk_step_kind(&as_enum, &short_set, &step_ids);
+            let step_visitors = crate::step_visitor::mk_step_visitors(&as_enum, paths);
+            let step_codec = if step_attr.codec {
+                Some(crate::step_codec::mk_step_codec(&as_enum, &step_ids, step_attr.codec_tests))
+            } else {
+                None
+            };
+            let step_display = crate::step_display::mk_step_display(&as_enum, &templates);
+            let step_json = if step_attr.json {
+                Some(crate::step_json::mk_step_json(&as_enum, &short_set, paths))
+            } else {
+                None
+            };
+
+            TokenStream::from(quote! {
+                #as_enum
+                #short_name_getters
+                #name_getters
+                #info_getters
+                #step_kind
+                #step_visitors
+                #step_checker
+                #step_codec
+                #step_json
+                #step_display
+                #(#cnstr_impls)*
+            })
+        },
+        // The enum itself is still emitted (with the helper attributes
+        // stripped) so that only the macro's own errors get reported.
+        (errors, ..) => {
+            let compile_error = errors.err().map(|e| e.to_compile_error());
+            TokenStream::from(quote! {
+                #compile_error
+                #as_enum
+            })
+        }
+    }
 }
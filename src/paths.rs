@@ -0,0 +1,100 @@
+use syn::{ parse_quote,
+           parse::ParseStream,
+           parse::Result,
+           ext::IdentExt,
+           Ident,
+           Token };
+
+// Where the generated code looks for the runtime half of the tracer
+// (`Step`, `TraceMgr`, `StepInfo`, `HasInsertItem`, `Tracer`, `SafetyIdx`).
+// Defaults to `crate::trace`, which is where nanoda keeps it; the
+// `crate = ..` and `trace_mod = ..` options move the whole module, and
+// `step_type`, `mgr_type` and `info_type` override single items.
+#[derive(Clone)]
+pub struct TracePaths {
+    pub trace_mod : syn::Path,
+    pub step_type : Option<syn::Path>,
+    pub mgr_type : Option<syn::Path>,
+    pub info_type : Option<syn::Path>,
+}
+
+impl Default for TracePaths {
+    fn default() -> Self {
+        TracePaths {
+            trace_mod : parse_quote!(crate::trace),
+            step_type : None,
+            mgr_type : None,
+            info_type : None,
+        }
+    }
+}
+
+impl TracePaths {
+    fn in_trace_mod(&self, item : &str) -> syn::Path {
+        let mut path = self.trace_mod.clone();
+        path.segments.push(Ident::new(item, proc_macro2::Span::call_site()).into());
+        path
+    }
+
+    pub fn step(&self) -> syn::Path {
+        self.step_type.clone().unwrap_or_else(|| self.in_trace_mod("Step"))
+    }
+
+    pub fn mgr(&self) -> syn::Path {
+        self.mgr_type.clone().unwrap_or_else(|| self.in_trace_mod("TraceMgr"))
+    }
+
+    pub fn info(&self) -> syn::Path {
+        self.info_type.clone().unwrap_or_else(|| self.in_trace_mod("StepInfo"))
+    }
+
+    pub fn has_insert_item(&self) -> syn::Path {
+        self.in_trace_mod("HasInsertItem")
+    }
+
+    pub fn tracer(&self) -> syn::Path {
+        self.in_trace_mod("Tracer")
+    }
+
+    pub fn safety_idx(&self) -> syn::Path {
+        self.in_trace_mod("SafetyIdx")
+    }
+
+    // Tries to consume the value of a `key = value` option that names
+    // a path. Returns `Ok(false)` without consuming anything if `key`
+    // isn't one of the path options, so callers can check their own keys.
+    pub fn parse_option(&mut self, key : &Ident, input : ParseStream) -> Result<bool> {
+        if key == "crate" {
+            let crate_path = input.call(syn::Path::parse_mod_style)?;
+            self.trace_mod = parse_quote!(#crate_path::trace);
+        } else if key == "trace_mod" {
+            self.trace_mod = input.call(syn::Path::parse_mod_style)?;
+        } else if key == "step_type" {
+            self.step_type = Some(input.parse()?);
+        } else if key == "mgr_type" {
+            self.mgr_type = Some(input.parse()?);
+        } else if key == "info_type" {
+            self.info_type = Some(input.parse()?);
+        } else {
+            return Ok(false)
+        }
+        Ok(true)
+    }
+}
+
+// Parses the `key =` half of a `key = value` option. Keys are parsed
+// with `parse_any` so that `crate = ..` is accepted.
+pub fn parse_option_key(input : ParseStream) -> Result<Ident> {
+    let key = input.call(Ident::parse_any)?;
+    input.parse::<Token![=]>()?;
+    Ok(key)
+}
+
+// Skips the value of an option that wasn't recognized, up to the next
+// top-level comma, so parsing can carry on and report later errors too.
+pub fn skip_option_value(input : ParseStream) -> Result<()> {
+    while !input.is_empty() && !input.peek(Token![,]) {
+        input.parse::<proc_macro2::TokenTree>()?;
+    }
+    Ok(())
+}
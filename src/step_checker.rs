@@ -0,0 +1,157 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{ quote, format_ident };
+use syn::{ parse::Result,
+           Ident };
+
+use crate::helpers::{ Errors, hygienic_ident, snake_case_name };
+use crate::paths::TracePaths;
+use crate::step_derive::{ cfg_attrs, field_item_type, info_member };
+use crate::step_kind::kind_ident;
+use crate::step_visitor::{ visited_fields, VisitedField };
+
+// `StepChecker` (named after the step enum) : the skeleton of a checker
+// that replays a finished trace. There's one `check_<snake_name>` method
+// per variant, taking the step's info, its fields and the step's result.
+// Fields marked `#[item(T)]` are resolved to a `&T` through
+// `Items : Index<FieldIdx, Output = T>`; others are passed as they are.
+// `Step::check_trace` runs a checker over the steps in the order they were
+// traced, which is children first, and stops at the first failure.
+//
+// Has to run before the `#[item]` attributes are stripped.
+pub fn mk_step_checker(base_enum : &syn::ItemEnum, paths : &TracePaths) -> Result<TokenStream2> {
+    let step_ident = &base_enum.ident;
+    let vis = &base_enum.vis;
+    let (impl_generics, ty_generics, where_clause) = base_enum.generics.split_for_impl();
+    let checker_ident = format_ident!("{}Checker", step_ident);
+    let failure_ident = format_ident!("{}CheckFailure", step_ident);
+    let kind_ident = kind_ident(step_ident);
+    let checker_doc = format!("Checks each kind of [`{}`] when replaying a trace with `{}::check_trace`.", step_ident, step_ident);
+    let failure_doc = format!("The first step of a trace that a [`{}`] rejected.", checker_ident);
+    let mut errors = Errors::default();
+    // The locals of `check_trace`; hygienic, so fields of the same names
+    // don't clash with them.
+    let items = hygienic_ident("items");
+    let checker = hygienic_ident("checker");
+    let result = hygienic_ident("result");
+
+    // `Index` bounds on `Items`, one per field type that's resolved.
+    let mut resolved = Vec::<(syn::Type, syn::Type)>::new();
+    let mut methods = Vec::<TokenStream2>::new();
+    let mut check_arms = Vec::<TokenStream2>::new();
+
+    for v in base_enum.variants.iter() {
+        let variant_ident = &v.ident;
+        let method_ident = format_ident!("check_{}", snake_case_name(variant_ident));
+        let cfgs = cfg_attrs(v);
+        let info_member = info_member(v, paths);
+
+        let mut params = Vec::<TokenStream2>::new();
+        let mut field_pats = Vec::<TokenStream2>::new();
+        let mut args = Vec::<TokenStream2>::new();
+
+        for VisitedField { member, name, binding, field } in visited_fields(v, paths) {
+            let field_type = &field.ty;
+            let item_type = if Some(&member) == info_member.as_ref() {
+                None
+            } else {
+                errors.take(field_item_type(field)).flatten()
+            };
+
+            field_pats.push(quote!(#member : #binding));
+
+            match item_type {
+                Some(item_type) => {
+                    match resolved.iter().find(|(idx_type, _)| quote!(#idx_type).to_string() == quote!(#field_type).to_string()) {
+                        Some((_, prev_item_type)) if quote!(#prev_item_type).to_string() != quote!(#item_type).to_string() => {
+                            errors.push(syn::Error::new_spanned(&item_type, format!("`{}` fields are resolved to `{}` elsewhere; \
+                                                                                    one index type can only resolve to one item type",
+                                                                                   quote!(#field_type), quote!(#prev_item_type))));
+                        },
+                        Some(..) => (),
+                        None => resolved.push((field_type.clone(), item_type.clone()))
+                    }
+                    params.push(quote! { #name : &#item_type });
+                    args.push(quote! { &#items[*#binding] });
+                },
+                None => {
+                    params.push(quote! { #name : &#field_type });
+                    args.push(quote! { #binding });
+                }
+            }
+        }
+
+        // A method takes every field of its variant, plus the items and result.
+        methods.push(quote! {
+            #(#cfgs)*
+            #[allow(clippy::too_many_arguments, clippy::ptr_arg)]
+            fn #method_ident(&mut self, #items : &Self::Items, #(#params,)* #result : Option<&Self::ResultItem>) -> Result<(), Self::Error>;
+        });
+        check_arms.push(quote! {
+            #(#cfgs)*
+            #step_ident::#variant_ident { #(#field_pats,)* .. } => #checker.#method_ident(#items, #(#args,)* #result),
+        });
+    }
+
+    errors.finish()?;
+
+    let index_bounds = resolved.iter().map(|(idx_type, item_type)| {
+        quote! { + std::ops::Index<#idx_type, Output = #item_type> }
+    });
+
+    Ok(quote! {
+        #[doc = #checker_doc]
+        #vis trait #checker_ident #impl_generics #where_clause {
+            /// Where the steps' items live.
+            type Items : ?Sized #(#index_bounds)*;
+            type ResultItem : ?Sized;
+            type Error;
+
+            /// The item a step produced, if it recorded one.
+            fn result_item<'i>(&self, items : &'i Self::Items, step : &#step_ident #ty_generics) -> Option<&'i Self::ResultItem>;
+
+            #(#methods)*
+        }
+
+        #[doc = #failure_doc]
+        #[derive(Debug, Clone, PartialEq)]
+        #vis struct #failure_ident<E> {
+            /// Position of the step in the trace.
+            pub index : usize,
+            pub kind : #kind_ident,
+            pub short_name : &'static str,
+            pub error : E,
+        }
+
+        impl<E : std::fmt::Display> std::fmt::Display for #failure_ident<E> {
+            fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "step {} ({}) failed to check : {}", self.index, self.short_name, self.error)
+            }
+        }
+
+        impl #impl_generics #step_ident #ty_generics #where_clause {
+            /// `steps` is expected in the order the steps were traced, so
+            /// each step is checked after its children.
+            pub fn check_trace<C : #checker_ident #ty_generics>(
+                steps : &[Self],
+                #items : &C::Items,
+                #checker : &mut C
+            ) -> Result<(), #failure_ident<C::Error>> {
+                for (index, step) in steps.iter().enumerate() {
+                    let #result = #checker.result_item(#items, step);
+                    let outcome = match step {
+                        #(#check_arms)*
+                    };
+                    if let Err(error) = outcome {
+                        return Err(#failure_ident {
+                            index,
+                            kind : step.kind(),
+                            short_name : step.get_step_name_string_short(),
+                            error,
+                        })
+                    }
+                }
+                Ok(())
+            }
+        }
+    })
+}
@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{ quote, format_ident };
+use syn::{ parse_quote,
+           Ident };
+
+use crate::helpers::snake_case_name;
+use crate::step_derive::cfg_attrs;
+
+// `Step::encode`/`Step::decode`, a compact binary format for traces : the
+// step's id as a varint, then each of its fields in declaration order. How
+// a field is written is up to the `StepCodec` trait (named after the step
+// enum), which is implemented here for unsigned integers (as LEB128
+// varints), `bool`, `Option` and `Vec`; the runtime implements it for its
+// index types and `StepInfo`, usually by delegating to those.
+pub fn mk_step_codec(base_enum : &syn::ItemEnum, ids : &HashMap<Ident, u16>, tests : bool) -> TokenStream2 {
+    let step_ident = &base_enum.ident;
+    let vis = &base_enum.vis;
+    let codec_ident = format_ident!("{}Codec", step_ident);
+    let codec_doc = format!("How the fields of a [`{}`] are written by `{}::encode` and read back by `{}::decode`.",
+                            step_ident, step_ident, step_ident);
+    let unknown_id_msg = format!("unknown {} id {{}}", step_ident);
+
+    // Concrete field types can be checked where the enum is defined; types
+    // that may mention the enum's generics need a bound.
+    let mut generics = base_enum.generics.clone();
+    if !generics.params.is_empty() {
+        let where_clause = generics.make_where_clause();
+        for field in base_enum.variants.iter().filter(|v| cfg_attrs(v).is_empty()).flat_map(|v| v.fields.iter()) {
+            let field_type = &field.ty;
+            where_clause.predicates.push(parse_quote!(#field_type : #codec_ident));
+        }
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let mut encode_arms = Vec::<TokenStream2>::new();
+    let mut decode_arms = Vec::<TokenStream2>::new();
+
+    for v in base_enum.variants.iter().filter(|v| ids.contains_key(&v.ident)) {
+        let variant_ident = &v.ident;
+        let id = ids[variant_ident];
+        let cfgs = cfg_attrs(v);
+        let members = v.fields.iter().enumerate().map(|(idx, field)| match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(syn::Index::from(idx))
+        }).collect::<Vec<syn::Member>>();
+        let bindings = (0..members.len()).map(|idx| format_ident!("f{}", idx)).collect::<Vec<Ident>>();
+
+        encode_arms.push(quote! {
+            #(#cfgs)*
+            Self::#variant_ident { #(#members : #bindings),* } => {
+                #codec_ident::encode_field(&#id, w)?;
+                #(#codec_ident::encode_field(#bindings, w)?;)*
+            },
+        });
+        decode_arms.push(quote! {
+            #(#cfgs)*
+            #id => Self::#variant_ident { #(#members : #codec_ident::decode_field(r)?),* },
+        });
+    }
+
+    let round_trip_tests = if tests {
+        mk_round_trip_tests(base_enum, ids)
+    } else {
+        quote!()
+    };
+
+    quote! {
+        #[doc = #codec_doc]
+        #vis trait #codec_ident : Sized {
+            fn encode_field(&self, w : &mut impl std::io::Write) -> std::io::Result<()>;
+            fn decode_field(r : &mut impl std::io::Read) -> std::io::Result<Self>;
+        }
+
+        impl #codec_ident for u64 {
+            fn encode_field(&self, w : &mut impl std::io::Write) -> std::io::Result<()> {
+                let mut n = *self;
+                loop {
+                    let byte = (n & 0x7f) as u8;
+                    n >>= 7;
+                    if n == 0 {
+                        return w.write_all(&[byte])
+                    }
+                    w.write_all(&[byte | 0x80])?;
+                }
+            }
+
+            fn decode_field(r : &mut impl std::io::Read) -> std::io::Result<Self> {
+                let mut n = 0u64;
+                let mut shift = 0;
+                loop {
+                    let mut byte = [0u8];
+                    r.read_exact(&mut byte)?;
+                    // The tenth byte only has room for the top bit, and ends the varint.
+                    if shift == 63 && byte[0] > 1 {
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "varint is too long"))
+                    }
+                    n |= u64::from(byte[0] & 0x7f) << shift;
+                    if byte[0] & 0x80 == 0 {
+                        return Ok(n)
+                    }
+                    shift += 7;
+                }
+            }
+        }
+
+        impl #codec_ident for usize {
+            fn encode_field(&self, w : &mut impl std::io::Write) -> std::io::Result<()> {
+                #codec_ident::encode_field(&(*self as u64), w)
+            }
+
+            fn decode_field(r : &mut impl std::io::Read) -> std::io::Result<Self> {
+                let n = <u64 as #codec_ident>::decode_field(r)?;
+                std::convert::TryFrom::try_from(n).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+            }
+        }
+
+        impl #codec_ident for u32 {
+            fn encode_field(&self, w : &mut impl std::io::Write) -> std::io::Result<()> {
+                #codec_ident::encode_field(&u64::from(*self), w)
+            }
+
+            fn decode_field(r : &mut impl std::io::Read) -> std::io::Result<Self> {
+                let n = <u64 as #codec_ident>::decode_field(r)?;
+                std::convert::TryFrom::try_from(n).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+            }
+        }
+
+        impl #codec_ident for u16 {
+            fn encode_field(&self, w : &mut impl std::io::Write) -> std::io::Result<()> {
+                #codec_ident::encode_field(&u64::from(*self), w)
+            }
+
+            fn decode_field(r : &mut impl std::io::Read) -> std::io::Result<Self> {
+                let n = <u64 as #codec_ident>::decode_field(r)?;
+                std::convert::TryFrom::try_from(n).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+            }
+        }
+
+        impl #codec_ident for u8 {
+            fn encode_field(&self, w : &mut impl std::io::Write) -> std::io::Result<()> {
+                w.write_all(&[*self])
+            }
+
+            fn decode_field(r : &mut impl std::io::Read) -> std::io::Result<Self> {
+                let mut byte = [0u8];
+                r.read_exact(&mut byte)?;
+                Ok(byte[0])
+            }
+        }
+
+        impl #codec_ident for bool {
+            fn encode_field(&self, w : &mut impl std::io::Write) -> std::io::Result<()> {
+                #codec_ident::encode_field(&(*self as u8), w)
+            }
+
+            fn decode_field(r : &mut impl std::io::Read) -> std::io::Result<Self> {
+                match <u8 as #codec_ident>::decode_field(r)? {
+                    0 => Ok(false),
+                    1 => Ok(true),
+                    _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid bool"))
+                }
+            }
+        }
+
+        impl<T : #codec_ident> #codec_ident for Option<T> {
+            fn encode_field(&self, w : &mut impl std::io::Write) -> std::io::Result<()> {
+                match self {
+                    Some(t) => {
+                        #codec_ident::encode_field(&true, w)?;
+                        t.encode_field(w)
+                    },
+                    None => #codec_ident::encode_field(&false, w)
+                }
+            }
+
+            fn decode_field(r : &mut impl std::io::Read) -> std::io::Result<Self> {
+                if <bool as #codec_ident>::decode_field(r)? {
+                    T::decode_field(r).map(Some)
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+
+        impl<T : #codec_ident> #codec_ident for Vec<T> {
+            fn encode_field(&self, w : &mut impl std::io::Write) -> std::io::Result<()> {
+                #codec_ident::encode_field(&self.len(), w)?;
+                for t in self.iter() {
+                    t.encode_field(w)?;
+                }
+                Ok(())
+            }
+
+            fn decode_field(r : &mut impl std::io::Read) -> std::io::Result<Self> {
+                let len = <usize as #codec_ident>::decode_field(r)?;
+                (0..len).map(|_| T::decode_field(&mut *r)).collect()
+            }
+        }
+
+        impl #impl_generics #step_ident #ty_generics #where_clause {
+            pub fn encode(&self, w : &mut impl std::io::Write) -> std::io::Result<()> {
+                match self {
+                    #(#encode_arms)*
+                }
+                Ok(())
+            }
+
+            pub fn decode(r : &mut impl std::io::Read) -> std::io::Result<Self> {
+                let id = <u16 as #codec_ident>::decode_field(r)?;
+                Ok(match id {
+                    #(#decode_arms)*
+                    _ => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!(#unknown_id_msg, id)))
+                })
+            }
+        }
+
+        #round_trip_tests
+    }
+}
+
+// One test per variant that encodes a step built from `Default` fields and
+// checks it decodes to the same step. Needs the step to be `PartialEq` and
+// `Debug`, and its fields to be `Default`.
+fn mk_round_trip_tests(base_enum : &syn::ItemEnum, ids : &HashMap<Ident, u16>) -> TokenStream2 {
+    let step_ident = &base_enum.ident;
+    let mod_ident = format_ident!("{}_codec_round_trip", snake_case_name(step_ident));
+
+    let test_fns = base_enum.variants.iter().filter(|v| ids.contains_key(&v.ident)).map(|v| {
+        let variant_ident = &v.ident;
+        let test_ident = snake_case_name(variant_ident);
+        let cfgs = cfg_attrs(v);
+        let members = v.fields.iter().enumerate().map(|(idx, field)| match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(syn::Index::from(idx))
+        });
+        quote! {
+            #(#cfgs)*
+            #[test]
+            fn #test_ident() {
+                let step = #step_ident::#variant_ident { #(#members : Default::default()),* };
+                let mut bytes = Vec::new();
+                step.encode(&mut bytes).unwrap();
+                let mut reader = bytes.as_slice();
+                assert_eq!(#step_ident::decode(&mut reader).unwrap(), step);
+                assert!(reader.is_empty());
+            }
+        }
+    });
+
+    quote! {
+        #[cfg(test)]
+        mod #mod_ident {
+            use super::*;
+
+            #(#test_fns)*
+        }
+    }
+}
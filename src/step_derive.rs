@@ -9,6 +9,7 @@ use syn::{ parse_macro_input,
            parse::ParseStream,
            parse::ParseBuffer,
            parse::Result,
+           ext::IdentExt,
            Ident,
            visit_mut::VisitMut, 
            ItemFn, 
@@ -18,78 +19,177 @@ use syn::{ parse_macro_input,
            Field,
            Stmt };
 
-use crate::helpers::{ fold_with, snake_case_name };
+use crate::helpers::{ snake_case_name, Errors };
+use crate::paths::{ TracePaths, skip_option_value };
+
+// Options passed to `#[is_step(..)]` : the paths of the runtime items the
+// generated code refers to, the fields declared common with `common(..)`,
+// whether every variant has to have a `#[step_id = N]`, and whether to
+// generate the binary codec (`codec`), round-trip tests for it
+// (`codec(tests)`), and the JSON Lines reader/writer (`json`).
+#[derive(Default)]
+pub struct StepAttr {
+    pub paths : TracePaths,
+    pub common : Vec<Ident>,
+    pub explicit_ids : bool,
+    pub codec : bool,
+    pub codec_tests : bool,
+    pub json : bool,
+}
+
+impl StepAttr {
+    // Malformed attribute syntax is an error, but options that are merely
+    // unknown or misplaced are collected separately, so the options that
+    // did parse are kept and the rest of the macro can still be checked.
+    pub fn parse(input : ParseStream) -> Result<(StepAttr, Errors)> {
+        let mut step_attr = StepAttr::default();
+        let mut errors = Errors::default();
+
+        while !input.is_empty() {
+            let key = input.call(Ident::parse_any)?;
+            if input.peek(syn::token::Paren) {
+                // `common(a, b)`, `codec(tests)`
+                let content;
+                syn::parenthesized!(content in input);
+                if key == "common" {
+                    step_attr.common.extend(Punctuated::<Ident, Comma>::parse_terminated(&content)?);
+                } else if key == "codec" {
+                    step_attr.codec = true;
+                    for codec_option in Punctuated::<Ident, Comma>::parse_terminated(&content)? {
+                        if codec_option == "tests" {
+                            step_attr.codec_tests = true;
+                        } else {
+                            errors.push(syn::Error::new(codec_option.span(), format!("unknown codec option `{}`", codec_option)));
+                        }
+                    }
+                } else {
+                    errors.push(syn::Error::new(key.span(), format!("unknown option `{}` in is_step attribute", key)));
+                }
+            } else if input.parse::<Option<syn::Token![=]>>()?.is_some() {
+                if key == "step_type" {
+                    errors.push(syn::Error::new(key.span(), "is_step generates its impls for the enum it's on; \
+                                                             `step_type` only applies to `#[trace]`"));
+                    skip_option_value(input)?;
+                } else if !step_attr.paths.parse_option(&key, input)? {
+                    errors.push(syn::Error::new(key.span(), format!("unknown option `{}` in is_step attribute", key)));
+                    skip_option_value(input)?;
+                }
+            } else if key == "explicit_ids" {
+                step_attr.explicit_ids = true;
+            } else if key == "codec" {
+                step_attr.codec = true;
+            } else if key == "json" {
+                step_attr.json = true;
+            } else {
+                errors.push(syn::Error::new(key.span(), format!("unknown option `{}` in is_step attribute", key)));
+                skip_option_value(input)?;
+            }
+            if input.parse::<Option<Comma>>()?.is_none() && !input.is_empty() {
+                return Err(input.error("options to the is_step attribute must be separated by commas"))
+            }
+        }
+
+        Ok((step_attr, errors))
+    }
+}
+
+// A constructor argument : the member it's stored in, the argument's
+// name (`l` for a field `l : ExprIdx`, `a0` for the tuple field `.0`),
+// and the item type from `#[item(..)]`, if the field has one.
+pub struct CnstrArg {
+    pub member : syn::Member,
+    pub ident : Ident,
+    pub item_type : Option<syn::Type>,
+}
+
+// How a variant's constructor fills the variant in : which member (if
+// any) gets a fresh `StepInfo`, which members are built from the
+// constructor's arguments, and which common fields other than the info
+// are left at `Default::default()`.
+pub struct CnstrFields {
+    pub info_member : Option<syn::Member>,
+    pub args : Vec<CnstrArg>,
+    pub defaulted : Vec<syn::Member>,
+}
 
 // Operates on one particular enum variant; meant to be mapped
 // over the list of enum variants taken from `DeriveInput`.
 
 // Creates an associated method for TraceMgr<T : Tracer> 
-// that constructs a new step. Is slightly less type safe than we'd like
-// since it's generic over items that implement HasInsertItem
-pub fn gen_cnstr_one(variant_ident : &syn::Ident, unique_fields : Vec<Field>) -> syn::ItemImpl {
+// that constructs a new step. Arguments for fields with an `#[item(..)]`
+// type take that type; the rest are generic over items that implement
+// HasInsertItem, which is slightly less type safe than we'd like.
+pub fn gen_cnstr_one(step_ident : &Ident, generics : &syn::Generics, variant : &Variant, cnstr_fields : CnstrFields, paths : &TracePaths) -> syn::ItemImpl {
+    let variant_ident = &variant.ident;
+    let cfgs = cfg_attrs(variant);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let tracer_param = fresh_type_param(generics);
+    let mgr_type = paths.mgr();
+    let info_type = paths.info();
+    let has_insert_item = paths.has_insert_item();
+    let tracer = paths.tracer();
+
     // Name of constructor method, IE `EqCore enum variant uses new_eq_core`
     let method_name = format_ident!("new_{}", snake_case_name(variant_ident));
 
     // make the list of arguments to the constructor method (minus `&mut self`)
-    let fn_args_list = unique_fields.iter().map(|field| {
-        let unique_ident = field.ident.as_ref().expect("Field should have ident");
-        let fn_arg_item : syn::FnArg = parse_quote! {
-            #unique_ident : impl HasInsertItem
+    let fn_args_list = cnstr_fields.args.iter().map(|CnstrArg { ident : arg_ident, item_type, .. }| {
+        let fn_arg_item : syn::FnArg = match item_type {
+            Some(item_type) => parse_quote! { #arg_ident : #item_type },
+            None => parse_quote! { #arg_ident : impl #has_insert_item }
         };
         fn_arg_item
     }).collect::<Punctuated<syn::FnArg, syn::token::Comma>>();
 
     // Make the statemetns inserting/assigning the item indexes
 
-    let idx_assn_stmts = unique_fields.iter().map(|field| {
-        let unique_ident = &field.ident;
-        let idx_ident = format_ident!("{}_idx", field.ident.as_ref().expect("Field should have ident"));
+    let idx_assn_stmts = cnstr_fields.args.iter().map(|CnstrArg { ident : arg_ident, .. }| {
+        let idx_ident = format_ident!("{}_idx", arg_ident);
         let assn_stmt : syn::Stmt = parse_quote! {
-            let #idx_ident = #unique_ident.insert_item(&mut self.item_storage);
+            let #idx_ident = #has_insert_item::insert_item(#arg_ident, &mut self.item_storage);
         };
         assn_stmt
-    }).collect::<Punctuated<syn::Stmt, syn::token::Semi>>();
+    }).collect::<Vec<syn::Stmt>>();
 
-    // Make the value fields for the return enum
-    let enum_field_vals = unique_fields.iter().map(|field| {
-        let unique_ident = &field.ident;
-        let idx_ident = format_ident!("{}_idx", field.ident.as_ref().expect("Field should have ident"));
+    // Make the value fields for the return enum; tuple variants use the
+    // `Variant { 0 : .., 1 : .. }` form, so every shape is built the same way.
+    let mut enum_field_vals = cnstr_fields.args.iter().map(|CnstrArg { member, ident : arg_ident, .. }| {
+        let idx_ident = format_ident!("{}_idx", arg_ident);
         let this_field_val : syn::FieldValue = parse_quote! {
-            #unique_ident : #idx_ident
+            #member : #idx_ident
         };
         this_field_val
     }).collect::<Punctuated<syn::FieldValue, syn::token::Comma>>();
 
+    for member in cnstr_fields.defaulted.iter() {
+        enum_field_vals.push(parse_quote! {
+            #member : Default::default()
+        });
+    }
 
+    if let Some(info_member) = &cnstr_fields.info_member {
+        enum_field_vals.insert(0, parse_quote! {
+            #info_member : #info_type::new(self.next_safety_idx())
+        });
+    }
 
-    //let variant_ident_w_path : syn::Path = parse_quote!("crate::trace::Step::{}", variant_ident);
     let variant_ident_w_path : syn::Path = parse_quote! {
-        crate::trace::Step::#variant_ident
+        #step_ident::#variant_ident
     };
 
-    // Requires special handling if enum variant has no unique fields 
-    // (IE only contains StepInfo)
-    let mut enum_val : syn::ExprStruct = if unique_fields.is_empty() {
-        parse_quote! {
-            #variant_ident_w_path {
-                info : StepInfo::new(self.next_safety_idx()),
-            }
-        }
-    } else {
-        parse_quote! {
-            #variant_ident_w_path {
-                info : StepInfo::new(self.next_safety_idx()),
-                #enum_field_vals,
-            }
+    let enum_val : syn::ExprStruct = parse_quote! {
+        #variant_ident_w_path {
+            #enum_field_vals
         }
     };
 
     let enum_return_item = syn::Stmt::Expr(syn::Expr::Struct(enum_val));
 
-    let mut item_impl : syn::ItemImpl = parse_quote! {
-        impl<T : Tracer> TraceMgr<T> {
-            pub fn #method_name(&mut self, #fn_args_list) -> Step {
-                #idx_assn_stmts
+    let item_impl : syn::ItemImpl = parse_quote! {
+        #(#cfgs)*
+        impl<#tracer_param : #tracer> #mgr_type<#tracer_param> {
+            pub fn #method_name #impl_generics (&mut self, #fn_args_list) -> #step_ident #ty_generics #where_clause {
+                #(#idx_assn_stmts)*
                 #enum_return_item
             }
 
@@ -100,32 +200,56 @@ pub fn gen_cnstr_one(variant_ident : &syn::Ident, unique_fields : Vec<Field>) ->
     item_impl
 }
 
+// A variant's `#[cfg(..)]` attributes, to be put on everything generated
+// for it so that cfg'd out variants don't leave dangling constructors or
+// match arms behind.
+pub fn cfg_attrs(variant : &Variant) -> Vec<&syn::Attribute> {
+    variant.attrs.iter().filter(|attr| attr.path.is_ident("cfg")).collect()
+}
+
+// The constructors' impl is generic over the manager's tracer; pick a name
+// for that parameter that the step enum's own generics don't use.
+fn fresh_type_param(generics : &syn::Generics) -> Ident {
+    let taken = generics.type_params().map(|param| param.ident.to_string()).collect::<HashSet<String>>();
+    let mut name = String::from("T");
+    while taken.contains(&name) {
+        name.push('_');
+    }
+    format_ident!("{}", name)
+}
+
 
 
-pub fn mk_match_arm_one_short(variant_ident : &Ident, retval : &Ident) -> syn::Arm {
-    let variant_ident_string = variant_ident;
+pub fn mk_match_arm_one_short(variant : &Variant, retval : &Ident) -> syn::Arm {
+    let variant_ident = &variant.ident;
+    let cfgs = cfg_attrs(variant);
     let arm : syn::Arm = parse_quote! {
-        #variant_ident { .. } => stringify!(#retval)
+        #(#cfgs)*
+        Self::#variant_ident { .. } => stringify!(#retval)
     };
     arm
 }
 
-pub fn mk_match_arm_one(variant_ident : &syn::Ident) -> syn::Arm {
-    let variant_ident_string = variant_ident;
+pub fn mk_match_arm_one(variant : &Variant) -> syn::Arm {
+    let variant_ident = &variant.ident;
+    let cfgs = cfg_attrs(variant);
     let arm : syn::Arm = parse_quote! {
-        #variant_ident { .. } => stringify!(#variant_ident_string)
+        #(#cfgs)*
+        Self::#variant_ident { .. } => stringify!(#variant_ident)
     };
     arm
 }
 
 pub fn mk_name_getters2(base_enum : &syn::ItemEnum) -> syn::ItemImpl {
+    let step_ident = &base_enum.ident;
+    let (impl_generics, ty_generics, where_clause) = base_enum.generics.split_for_impl();
     let match_arms = base_enum
                      .variants
                      .iter()
-                     .map(|v| mk_match_arm_one(&v.ident))
+                     .map(mk_match_arm_one)
                      .collect::<Punctuated<syn::Arm, syn::token::Comma>>();
     let item : syn::ItemImpl = parse_quote! {
-        impl crate::trace::Step {
+        impl #impl_generics #step_ident #ty_generics #where_clause {
             pub fn get_step_name_string(&self) -> &'static str {
                 match self {
                     #match_arms
@@ -136,40 +260,34 @@ pub fn mk_name_getters2(base_enum : &syn::ItemEnum) -> syn::ItemImpl {
     item
 }
 
-pub fn mk_name_getters(original_input : &syn::DeriveInput) -> syn::ItemImpl {
-    let base_enum = match &original_input.data {
-        syn::Data::Enum(ref syn_data_enum) => syn_data_enum,
-        _ => panic!("Not an enum in mk_name_getters")
-    };
-    let match_arms = base_enum
-                     .variants
-                     .iter()
-                     .map(|v| mk_match_arm_one(&v.ident))
-                     .collect::<Punctuated<syn::Arm, syn::token::Comma>>();
+// Accessors for the `StepInfo` slot of each variant, so code generated
+// by `#[trace]` can move a step's info onto another step. Variants
+// without one (unit variants, tuple variants without an info slot)
+// give `None`.
+pub fn mk_info_getters(base_enum : &syn::ItemEnum, paths : &TracePaths) -> syn::ItemImpl {
+    let step_ident = &base_enum.ident;
+    let (impl_generics, ty_generics, where_clause) = base_enum.generics.split_for_impl();
+    let info_type = paths.info();
+    let arms = base_enum.variants.iter().map(|v| {
+        let variant_ident = &v.ident;
+        let cfgs = cfg_attrs(v);
+        match info_member(v, paths) {
+            Some(syn::Member::Named(member)) if member == "info" => quote! { #(#cfgs)* Self::#variant_ident { info, .. } => Some(info), },
+            Some(member) => quote! { #(#cfgs)* Self::#variant_ident { #member : info, .. } => Some(info), },
+            None => quote! { #(#cfgs)* Self::#variant_ident { .. } => None, }
+        }
+    }).collect::<Vec<TokenStream2>>();
     let item : syn::ItemImpl = parse_quote! {
-        impl crate::trace::Step {
-            pub fn get_step_name_string(&self) -> &'static str {
+        impl #impl_generics #step_ident #ty_generics #where_clause {
+            pub fn get_step_info(&self) -> Option<&#info_type> {
                 match self {
-                    #match_arms
+                    #(#arms)*
                 }
             }
-        }
-    };
-    item
-}
-
-pub fn mk_name_getters_short(kvs : HashMap<Ident, Ident>) -> syn::ItemImpl {
 
-
-    let match_arms = kvs
-                     .iter()
-                     .map(|(k, v)| mk_match_arm_one_short(k, v))
-                     .collect::<Punctuated<syn::Arm, syn::token::Comma>>();
-    let item : syn::ItemImpl = parse_quote! {
-        impl crate::trace::Step {
-            pub fn get_step_name_string_short(&self) -> &'static str {
+            pub fn get_mut_step_info(&mut self) -> Option<&mut #info_type> {
                 match self {
-                    #match_arms
+                    #(#arms)*
                 }
             }
         }
@@ -177,19 +295,18 @@ pub fn mk_name_getters_short(kvs : HashMap<Ident, Ident>) -> syn::ItemImpl {
     item
 }
 
-pub fn mk_short_name_getters(original_input : &syn::DeriveInput) -> syn::ItemImpl {
-    let base_enum = match &original_input.data {
-        syn::Data::Enum(ref syn_data_enum) => syn_data_enum,
-        _ => panic!("Not an enum in mk_name_getters")
-    };
+pub fn mk_name_getters_short(kvs : &[(Ident, Ident)], base_enum : &syn::ItemEnum) -> syn::ItemImpl {
+    let step_ident = &base_enum.ident;
+    let (impl_generics, ty_generics, where_clause) = base_enum.generics.split_for_impl();
+
     let match_arms = base_enum
                      .variants
                      .iter()
-                     .map(|v| mk_match_arm_one(&v.ident))
+                     .filter_map(|v| Some(mk_match_arm_one_short(v, short_name_of(kvs, &v.ident)?)))
                      .collect::<Punctuated<syn::Arm, syn::token::Comma>>();
     let item : syn::ItemImpl = parse_quote! {
-        impl crate::trace::Step {
-            pub fn get_step_name_string(&self) -> &'static str {
+        impl #impl_generics #step_ident #ty_generics #where_clause {
+            pub fn get_step_name_string_short(&self) -> &'static str {
                 match self {
                     #match_arms
                 }
@@ -200,113 +317,178 @@ pub fn mk_short_name_getters(original_input : &syn::DeriveInput) -> syn::ItemImp
 }
 
 
-// Given the derive input, 
-// 1. Make sure you have a struct-style enum
-// 2. collect the names of the unique fields
-// 3. Make a constructor for each enum variant.
-pub fn derive_cnstrs(original_input : &syn::DeriveInput) -> Vec<syn::ItemImpl> {
+// Tuple fields marked `#[info]` hold the variant's `StepInfo`.
+fn is_info_attr(attr : &syn::Attribute) -> bool {
+    attr.path.is_ident("info")
+}
 
+// Where a variant keeps its `StepInfo` : the `info` field of a struct-like
+// variant, or the tuple field marked `#[info]` (or failing that, the one
+// whose type is the info type). Unit variants don't have one.
+pub fn info_member(v : &Variant, paths : &TracePaths) -> Option<syn::Member> {
+    let info_type = paths.info();
+    let info_type_name = info_type.segments.last().map(|seg| &seg.ident);
+    match &v.fields {
+        syn::Fields::Named(fields_named) => {
+            fields_named.named
+            .iter()
+            .filter_map(|f| f.ident.as_ref())
+            .find(|ident| *ident == "info")
+            .map(|ident| syn::Member::Named(ident.clone()))
+        },
+        syn::Fields::Unnamed(fields_unnamed) => {
+            let is_info_type = |f : &&Field| match &f.ty {
+                syn::Type::Path(type_path) => type_path.path.segments.last().map(|seg| &seg.ident) == info_type_name,
+                _ => false
+            };
+            let fields = fields_unnamed.unnamed.iter().collect::<Vec<&Field>>();
+            fields.iter().position(|f| f.attrs.iter().any(is_info_attr))
+            .or_else(|| fields.iter().position(is_info_type))
+            .map(|idx| syn::Member::Unnamed(syn::Index::from(idx)))
+        },
+        syn::Fields::Unit => None
+    }
+}
 
-    match &original_input.data {
-        syn::Data::Enum(syn::DataEnum { variants, .. }) => {
+// Fields marked `#[common]` are shared by the variants rather than passed
+// to the constructors.
+fn is_common_attr(attr : &syn::Attribute) -> bool {
+    attr.path.is_ident("common")
+}
 
+// The names of the fields the constructors don't take as arguments : the
+// ones listed in `#[is_step(common(..))]` and the ones marked `#[common]`
+// in any variant. Fields are matched up across variants by name, so
+// same-named fields have to have the same type everywhere.
+fn common_field_names(variants : &Punctuated<Variant, Comma>, declared : &[Ident]) -> Result<HashSet<Ident>> {
+    let mut errors = Errors::default();
+    let mut types_by_name = HashMap::<Ident, &syn::Type>::new();
+    let mut common = declared.iter().cloned().collect::<HashSet<Ident>>();
+
+    for field in variants.iter().filter(|v| matches!(v.fields, syn::Fields::Named(..))).flat_map(|v| v.fields.iter()) {
+        let field_ident = match &field.ident {
+            Some(ident) => ident,
+            None => continue
+        };
+        if field.attrs.iter().any(is_common_attr) {
+            common.insert(field_ident.clone());
+        }
+        match types_by_name.get(field_ident) {
+            Some(prev_type) => {
+                let field_type = &field.ty;
+                if quote!(#prev_type).to_string() != quote!(#field_type).to_string() {
+                    errors.push(syn::Error::new_spanned(field_type, format!("field `{}` has type `{}` in another variant; \
+                                                                              fields with the same name need the same type",
+                                                                             field_ident, quote!(#prev_type))));
+                }
+            },
+            None => { types_by_name.insert(field_ident.clone(), &field.ty); }
+        }
+    }
 
-            let unique_fields_cumul = get_unique_fields(&variants);
+    for name in declared.iter().filter(|name| !types_by_name.contains_key(*name)) {
+        errors.push(syn::Error::new(name.span(), format!("`{}` is declared common but isn't a field of any variant", name)));
+    }
 
-            // This is the cumulative set of all unique fields
-            // In this case, anything other than "info".
+    errors.finish()?;
+    Ok(common)
+}
 
-            variants.iter().map(|v| {
-                let v_ident = v.ident.clone();
-                let this_variant_unique_fields = match v.fields {
-                    syn::Fields::Named(ref fields_named) => {
-                        fields_named.named.iter().filter(|f| unique_fields_cumul.contains(f))
-                        .cloned()
-                        .collect::<Vec<Field>>()
-                    },
-                    _ => panic!("Not named fields as required")
-                };
-                gen_cnstr_one(&v_ident, this_variant_unique_fields)
-            }).collect::<Vec<syn::ItemImpl>>()
+// Struct-like variants take their fields as arguments, except for the
+// info and the common fields; tuple variants take every field but the
+// info slot, positionally, as `a0, a1, ..`; unit variants take none.
+fn variant_cnstr_fields(v : &Variant, common : &HashSet<Ident>, paths : &TracePaths) -> Result<CnstrFields> {
+    let marked_info = v.fields.iter().filter(|f| f.attrs.iter().any(is_info_attr)).collect::<Vec<&Field>>();
+    if let [_, extra, ..] = marked_info.as_slice() {
+        return Err(syn::Error::new_spanned(extra, "only one field of a variant can be marked `#[info]`"))
+    }
 
+    let info_member = info_member(v, paths);
+    let mut errors = Errors::default();
+    let mut args = Vec::new();
+    let mut defaulted = Vec::new();
 
+    for (idx, field) in v.fields.iter().enumerate() {
+        let (member, arg_ident) = match &field.ident {
+            Some(ident) => (syn::Member::Named(ident.clone()), ident.clone()),
+            None => (syn::Member::Unnamed(syn::Index::from(idx)), format_ident!("a{}", idx))
+        };
+        let item_type = errors.take(field_item_type(field)).flatten();
+        let is_info = Some(&member) == info_member.as_ref();
+        let is_common = field.ident.as_ref().is_some_and(|ident| common.contains(ident));
 
-        },
-        syn::Data::Struct(..) => panic!("StepMk :: StepDerive requires an enum input, got a struct!"),
-        syn::Data::Union(..) => panic!("StepMk :: StepDerive requires an enum input, got a union!")
+        if is_info || is_common {
+            if let Some(item_type) = item_type {
+                errors.push(syn::Error::new_spanned(item_type, "`#[item(..)]` only applies to fields the constructor takes"));
+            }
+            if !is_info {
+                defaulted.push(member);
+            }
+        } else {
+            args.push(CnstrArg { member, ident : arg_ident, item_type });
+        }
     }
-}
 
-pub fn derive_cnstrs2(base_enum : &syn::ItemEnum) -> Vec<syn::ItemImpl> {
-
-    let unique_fields_cumul = get_unique_fields(&base_enum.variants);
-
-    // This is the cumulative set of all unique fields
-    // In this case, anything other than "info".
-    base_enum.variants.iter().map(|v| {
-        let v_ident = v.ident.clone();
-        let this_variant_unique_fields = match v.fields {
-            syn::Fields::Named(ref fields_named) => {
-                fields_named.named.iter().filter(|f| unique_fields_cumul.contains(f))
-                .cloned()
-                .collect::<Vec<Field>>()
-            },
-            _ => panic!("Not named fields as required")
-        };
-        gen_cnstr_one(&v_ident, this_variant_unique_fields)
-    }).collect::<Vec<syn::ItemImpl>>()
+    errors.finish()?;
+    Ok(CnstrFields { info_member, args, defaulted })
 }
 
-pub fn get_unique_fields(variants : &Punctuated<Variant, Comma>) -> HashSet<Field> {
-    fields_diff(variants)
+// `#[item(Expr)]` on a field makes its constructor argument an `Expr`
+// rather than any `impl HasInsertItem`.
+pub fn is_item_attr(attr : &syn::Attribute) -> bool {
+    attr.path.is_ident("item")
 }
 
-pub fn fields_inter(variants : &Punctuated<Variant, Comma>) -> HashSet<Field> {
-    let mut named_fields = 
-        variants
-        .iter()
-        .filter_map(|v| match &v.fields {
-            syn::Fields::Named(fields_named) => Some(fields_named.named.clone().into_iter().collect::<HashSet<Field>>()),
-            _ => None
-        }).collect::<Vec<HashSet<Field>>>();
+pub fn field_item_type(field : &Field) -> Result<Option<syn::Type>> {
+    let mut item_attrs = field.attrs.iter().filter(|attr| is_item_attr(attr));
+    let item_type = match item_attrs.next() {
+        Some(attr) => attr.parse_args::<syn::Type>()?,
+        None => return Ok(None)
+    };
+    if let Some(extra) = item_attrs.next() {
+        return Err(syn::Error::new_spanned(extra, "a field can only have one `#[item(..)]` attribute"))
+    }
+    Ok(Some(item_type))
+}
 
-    let mut acc_set = named_fields.pop().unwrap_or_else(|| HashSet::new());
+// Makes a constructor for each variant, collecting an error for every
+// variant whose fields can't be sorted out.
+fn cnstrs_for_variants(step_ident : &Ident, generics : &syn::Generics, variants : &Punctuated<Variant, Comma>, common : &[Ident], paths : &TracePaths) -> Result<Vec<syn::ItemImpl>> {
+    let common = common_field_names(variants, common)?;
+    let mut errors = Errors::default();
 
-    for s in named_fields.iter() {
-        let intersection = acc_set.intersection(s).cloned().collect::<HashSet<Field>>();
-        acc_set = intersection
-    }
+    let cnstrs = variants.iter().filter_map(|v| {
+        let this_variant_cnstr_fields = errors.take(variant_cnstr_fields(v, &common, paths))?;
+        Some(gen_cnstr_one(step_ident, generics, v, this_variant_cnstr_fields, paths))
+    }).collect::<Vec<syn::ItemImpl>>();
 
-    acc_set
+    errors.finish()?;
+    Ok(cnstrs)
 }
 
-pub fn fields_union(variants : &Punctuated<Variant, Comma>) -> HashSet<Field> {
-    let mut union = HashSet::new();
-    for elem in variants
-               .iter()
-               .filter_map(|v| match &v.fields {
-                   syn::Fields::Named(fields_named) => Some(fields_named.named.clone()),
-                   _ => None
-               }).flat_map(|named| named.into_iter()) {
-                   union.insert(elem);
-               }
-    union
+// `#[info]`, `#[common]` and `#[item]` only mean something to this macro;
+// strip them so the enum that's emitted compiles.
+pub fn strip_field_attrs(base_enum : &mut syn::ItemEnum) {
+    for field in base_enum.variants.iter_mut().flat_map(|v| v.fields.iter_mut()) {
+        field.attrs.retain(|attr| !is_info_attr(attr) && !is_common_attr(attr) && !is_item_attr(attr));
+    }
 }
 
-pub fn fields_diff(variants : &Punctuated<Variant, Comma>) -> HashSet<Field> {
-    let union = fields_union(variants);
-    let inter = fields_inter(variants);
-
-    union.difference(&inter).cloned().collect::<HashSet<Field>>()
+pub fn derive_cnstrs2(base_enum : &syn::ItemEnum, step_attr : &StepAttr) -> Result<Vec<syn::ItemImpl>> {
+    cnstrs_for_variants(&base_enum.ident, &base_enum.generics, &base_enum.variants, &step_attr.common, &step_attr.paths)
 }
 
+// Looks a variant's short name up in what `collect_short_attrs` returned.
+pub fn short_name_of<'a>(short_names : &'a [(Ident, Ident)], variant_ident : &Ident) -> Option<&'a Ident> {
+    short_names.iter().find(|(k, _)| k == variant_ident).map(|(_, v)| v)
+}
 
-
-// Result HashMap has the complete set of variants even if not all
-// have #[short(..)] attributes; for those without, it just uses
+// Result has the complete set of variants, in declaration order, even if
+// not all have #[short(..)] attributes; for those without, it just uses
 // the long name.
-pub fn collect_short_attrs(base_enum : &mut syn::ItemEnum) -> HashMap<Ident, Ident> {
-    let mut acc = HashMap::<Ident, Ident>::new();
+pub fn collect_short_attrs(base_enum : &mut syn::ItemEnum) -> Result<Vec<(Ident, Ident)>> {
+    let mut acc = Vec::<(Ident, Ident)>::new();
+    let mut errors = Errors::default();
 
     let desired_path : syn::Path = parse_quote!(short);
 
@@ -314,37 +496,54 @@ pub fn collect_short_attrs(base_enum : &mut syn::ItemEnum) -> HashMap<Ident, Ide
                   .variants
                   .iter_mut() {
         let variant_ident = variant.ident.clone();
-        let taken_attrs = std::mem::replace(&mut variant.attrs, Vec::new());
-        if let Some(short_attr) = taken_attrs
+        if let Some(short_attr) = variant.attrs
                                   .iter()
-                                  .find(|attr| &attr.path == &desired_path) {
-            let parsed_attr = short_attr.parse_meta().expect("`short` attr was in the wrong format; could not be parsed as inert attribute");
-
-            match parsed_attr {
-                syn::Meta::List(syn::MetaList { nested, .. }) => {
-                    match nested.first().expect("Failed to get first element of `nested` when parsing short attr") {
-                        syn::NestedMeta::Meta(syn::Meta::Path(syn::Path { segments, .. })) => {
-                            let taken_ident = segments.first().expect("Failed to get ident from leading path segment in short attr").ident.clone();
-                            acc.insert(variant_ident, taken_ident);
-                        },
-                        _ => panic!("Attribute list for `short` was empty; needs to have the desired name in parenthesis!")
-                    }
-                },
-                _ => panic!("`short` attribute must be followed by the desired short name in parenthesis, ie `#[short(AQ)]")
+                                  .find(|attr| attr.path == desired_path) {
+            if let Some(taken_ident) = errors.take(parse_short_attr(short_attr)) {
+                acc.push((variant_ident, taken_ident));
             }
-
         } else {
-            acc.insert(variant_ident.clone(), variant_ident);
+            acc.push((variant_ident.clone(), variant_ident));
         }
+        // Only `#[short]` is ours; docs, `#[cfg]`, serde attributes etc. stay.
+        variant.attrs.retain(|attr| attr.path != desired_path);
     }
 
-    let mut nodup_set = HashSet::<&Ident>::new();
-    for (k, v) in acc.iter() {
-        if !nodup_set.insert(v) {
-            panic!("Duplicate short names found : identifier {} is used more than once!", v)
+    // Short names can't repeat, and can't be another variant's long name
+    // either, or `StepKind::from_str` couldn't tell them apart.
+    let mut claimed = acc.iter().map(|(variant_ident, _)| (variant_ident.to_string(), variant_ident)).collect::<HashMap<String, &Ident>>();
+    for (variant_ident, short_ident) in acc.iter() {
+        match claimed.get(&short_ident.to_string()) {
+            Some(owner) if *owner != variant_ident => {
+                let msg = if *owner == short_ident {
+                    format!("Duplicate short names found : identifier {} is already the name of a variant!", short_ident)
+                } else {
+                    format!("Duplicate short names found : identifier {} is already the short name of {}!", short_ident, owner)
+                };
+                errors.push(syn::Error::new(short_ident.span(), msg));
+            },
+            _ => { claimed.insert(short_ident.to_string(), variant_ident); }
         }
     }
 
+    errors.finish()?;
+    Ok(acc)
+}
 
-    acc
+// `#[short(AQ)]` => `AQ`
+fn parse_short_attr(short_attr : &syn::Attribute) -> Result<Ident> {
+    let malformed = || syn::Error::new_spanned(short_attr, "`short` attribute must be followed by the desired short name \
+                                                            in parenthesis, ie `#[short(AQ)]`");
+
+    match short_attr.parse_meta()? {
+        syn::Meta::List(syn::MetaList { nested, .. }) => {
+            match nested.first() {
+                Some(syn::NestedMeta::Meta(syn::Meta::Path(path))) if nested.len() == 1 => {
+                    path.get_ident().cloned().ok_or_else(malformed)
+                },
+                _ => Err(malformed())
+            }
+        },
+        _ => Err(malformed())
+    }
 }
@@ -0,0 +1,190 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{ quote, format_ident };
+use syn::{ parse::Result,
+           Ident,
+           LitStr,
+           Variant };
+
+use crate::helpers::{ Errors, hygienic_ident };
+use crate::step_derive::cfg_attrs;
+
+// The `#[step_display("..")]` and `#[step_explain("..")]` templates of one
+// variant. They're prefixed so they don't take the `#[display]` attributes
+// of derives like derive_more's `Display`.
+pub struct VariantTemplates {
+    pub variant_ident : Ident,
+    pub display : Option<LitStr>,
+    pub explain : Option<LitStr>,
+}
+
+fn is_template_attr(attr : &syn::Attribute) -> bool {
+    attr.path.is_ident("step_display") || attr.path.is_ident("step_explain")
+}
+
+// Collects each variant's templates, checking that their placeholders name
+// the variant's fields, and strips the attributes.
+pub fn collect_templates(base_enum : &mut syn::ItemEnum) -> Result<Vec<VariantTemplates>> {
+    let mut errors = Errors::default();
+    let mut acc = Vec::new();
+
+    for variant in base_enum.variants.iter_mut() {
+        let mut templates = VariantTemplates { variant_ident : variant.ident.clone(), display : None, explain : None };
+        for attr in variant.attrs.iter().filter(|attr| is_template_attr(attr)) {
+            let slot = if attr.path.is_ident("step_display") { &mut templates.display } else { &mut templates.explain };
+            if slot.is_some() {
+                errors.push(syn::Error::new_spanned(attr, "a variant can only have one of each template attribute"));
+                continue
+            }
+            if let Some(template) = errors.take(attr.parse_args::<LitStr>()) {
+                if errors.take(rewrite_template(&template, variant)).is_some() {
+                    *slot = Some(template);
+                }
+            }
+        }
+        variant.attrs.retain(|attr| !is_template_attr(attr));
+        acc.push(templates);
+    }
+
+    errors.finish()?;
+    Ok(acc)
+}
+
+// A placeholder's field : a named field by name, a tuple field by position.
+fn placeholder_binding(name : &str, variant : &Variant) -> Option<(syn::Member, Ident)> {
+    variant.fields.iter().enumerate().find_map(|(idx, field)| match &field.ident {
+        Some(ident) if ident == name => Some((syn::Member::Named(ident.clone()), ident.clone())),
+        None if idx.to_string() == name => Some((syn::Member::Unnamed(syn::Index::from(idx)), format_ident!("a{}", idx))),
+        _ => None
+    })
+}
+
+// Turns a template into a format string whose placeholders are named
+// arguments, along with the fields those refer to : `"{l} ≡ {0:?}"` becomes
+// `"{l} ≡ {a0:?}"` with `l` and `0`. Errors for placeholders that don't name
+// a field of the variant.
+fn rewrite_template(template : &LitStr, variant : &Variant) -> Result<(String, Vec<(syn::Member, Ident)>)> {
+    let text = template.value();
+    let mut format_string = String::new();
+    let mut used = Vec::<(syn::Member, Ident)>::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                format_string.push_str("{{");
+            },
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(syn::Error::new(template.span(), "unclosed `{` in template"))
+                    }
+                }
+                let (name, spec) = match placeholder.find(':') {
+                    Some(colon) => placeholder.split_at(colon),
+                    None => (placeholder.as_str(), "")
+                };
+                let name = name.trim();
+                if name.is_empty() {
+                    return Err(syn::Error::new(template.span(), "template placeholders have to name a field, like `{l}` or `{0}`"))
+                }
+                let (member, binding) = placeholder_binding(name, variant).ok_or_else(|| {
+                    syn::Error::new(template.span(), format!("`{}` has no field `{}`", variant.ident, name))
+                })?;
+                format_string.push_str(&format!("{{{}{}}}", binding, spec));
+                if !used.iter().any(|(used_member, _)| *used_member == member) {
+                    used.push((member, binding));
+                }
+            },
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                format_string.push_str("}}");
+            },
+            '}' => return Err(syn::Error::new(template.span(), "unmatched `}` in template; write `}}` for a literal `}`")),
+            c => format_string.push(c)
+        }
+    }
+
+    Ok((format_string, used))
+}
+
+// `Self::V { l, 0 : a0, .. } => write!(f, "{l} {a0}", l = l, a0 = a0)`
+fn template_arm(variant : &Variant, template : &LitStr, write_to : &TokenStream2) -> TokenStream2 {
+    let variant_ident = &variant.ident;
+    let cfgs = cfg_attrs(variant);
+    // Already checked by `collect_templates`.
+    let (format_string, used) = rewrite_template(template, variant).expect("template was checked");
+    let field_pats = used.iter().map(|(member, binding)| match member {
+        syn::Member::Named(_) => quote!(#binding),
+        syn::Member::Unnamed(_) => quote!(#member : #binding)
+    });
+    let bindings = used.iter().map(|(_, binding)| binding);
+    let format_lit = LitStr::new(&format_string, template.span());
+    quote! {
+        #(#cfgs)*
+        Self::#variant_ident { #(#field_pats,)* .. } => write!(#write_to, #format_lit #(, #bindings = #bindings)*),
+    }
+}
+
+// `impl Display for Step`, using each variant's `#[step_display]` template
+// (or its name), and `Step::explain()`, using the `#[step_explain]` template
+// (or the display). Nothing is generated if no variant has a template, so a
+// hand-written `Display` keeps working.
+pub fn mk_step_display(base_enum : &syn::ItemEnum, templates : &[VariantTemplates]) -> TokenStream2 {
+    if templates.iter().all(|t| t.display.is_none() && t.explain.is_none()) {
+        return quote!()
+    }
+
+    let step_ident = &base_enum.ident;
+    let (impl_generics, ty_generics, where_clause) = base_enum.generics.split_for_impl();
+    let formatter = hygienic_ident("f");
+    let explanation = hygienic_ident("explanation");
+
+    let template_of = |variant : &Variant| templates.iter().find(|t| t.variant_ident == variant.ident);
+
+    let display_arms = base_enum.variants.iter().map(|v| {
+        match template_of(v).and_then(|t| t.display.as_ref()) {
+            Some(display) => template_arm(v, display, &quote!(#formatter)),
+            None => {
+                let variant_ident = &v.ident;
+                let cfgs = cfg_attrs(v);
+                quote! { #(#cfgs)* Self::#variant_ident { .. } => #formatter.write_str(self.get_step_name_string()), }
+            }
+        }
+    });
+
+    let explain_arms = base_enum.variants.iter().map(|v| {
+        match template_of(v).and_then(|t| t.explain.as_ref()) {
+            Some(explain) => template_arm(v, explain, &quote!(#explanation)),
+            None => {
+                let variant_ident = &v.ident;
+                let cfgs = cfg_attrs(v);
+                quote! { #(#cfgs)* Self::#variant_ident { .. } => write!(#explanation, "{}", self), }
+            }
+        }
+    });
+
+    quote! {
+        impl #impl_generics std::fmt::Display for #step_ident #ty_generics #where_clause {
+            fn fmt(&self, #formatter : &mut std::fmt::Formatter) -> std::fmt::Result {
+                match self {
+                    #(#display_arms)*
+                }
+            }
+        }
+
+        impl #impl_generics #step_ident #ty_generics #where_clause {
+            pub fn explain(&self) -> String {
+                use std::fmt::Write;
+                let mut #explanation = String::new();
+                let _ = match self {
+                    #(#explain_arms)*
+                };
+                #explanation
+            }
+        }
+    }
+}
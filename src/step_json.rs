@@ -0,0 +1,446 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{ quote, format_ident };
+use syn::{ parse_quote,
+           Ident };
+
+use crate::paths::TracePaths;
+use crate::step_derive::{ cfg_attrs, info_member, short_name_of };
+
+// `Step::write_json`, one JSON object per step for JSON Lines traces :
+// `{"kind":"EqCore","short":"EC","fields":{"l":12,"r":40},..}` followed by
+// the entries of the step's info (say `"idx"`, `"result"`, `"children"`),
+// and `Step::read_json`/`read_json_lines` to read them back. Values go
+// through the `StepJson` trait (named after the step enum), implemented
+// here for unsigned integers, `bool`, `String`, `Option` and `Vec`; the
+// runtime implements it for its index types and `StepInfo`. There are no
+// dependencies, so this comes with a small JSON value type and parser.
+pub fn mk_step_json(base_enum : &syn::ItemEnum, short_names : &[(Ident, Ident)], paths : &TracePaths) -> TokenStream2 {
+    let step_ident = &base_enum.ident;
+    let vis = &base_enum.vis;
+    let json_ident = format_ident!("{}Json", step_ident);
+    let value_ident = format_ident!("{}JsonValue", step_ident);
+    let json_doc = format!("How the fields of a [`{}`] are written by `{}::write_json` and read back by `{}::read_json`.",
+                           step_ident, step_ident, step_ident);
+    let value_doc = format!("A JSON value, as written and read by [`{}`].", json_ident);
+    let uints = [quote!(u64), quote!(u32), quote!(u16), quote!(u8), quote!(usize)];
+
+    // As for the codec, fields whose types may mention the enum's generics
+    // need a bound.
+    let mut generics = base_enum.generics.clone();
+    if !generics.params.is_empty() {
+        let where_clause = generics.make_where_clause();
+        for field in base_enum.variants.iter().filter(|v| cfg_attrs(v).is_empty()).flat_map(|v| v.fields.iter()) {
+            let field_type = &field.ty;
+            where_clause.predicates.push(parse_quote!(#field_type : #json_ident));
+        }
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let mut to_json_arms = Vec::<TokenStream2>::new();
+    let mut from_json_arms = Vec::<TokenStream2>::new();
+
+    for v in base_enum.variants.iter() {
+        let variant_ident = &v.ident;
+        let cfgs = cfg_attrs(v);
+        let info_member = info_member(v, paths);
+
+        let members = v.fields.iter().enumerate().map(|(idx, field)| match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(syn::Index::from(idx))
+        }).collect::<Vec<syn::Member>>();
+        let bindings = (0..members.len()).map(|idx| format_ident!("f{}", idx)).collect::<Vec<Ident>>();
+
+        let mut field_entries = Vec::<TokenStream2>::new();
+        let mut info_entries = None;
+        let mut field_vals = Vec::<TokenStream2>::new();
+        for (member, binding) in members.iter().zip(bindings.iter()) {
+            if Some(member) == info_member.as_ref() {
+                info_entries = Some(quote! {
+                    match #json_ident::to_json(#binding) {
+                        #value_ident::Object(info_entries) => entries.extend(info_entries),
+                        info => entries.push(("info".to_string(), info)),
+                    }
+                });
+                field_vals.push(quote! { #member : #json_ident::from_json(info)? });
+            } else {
+                let key = match member {
+                    syn::Member::Named(ident) => ident.to_string(),
+                    syn::Member::Unnamed(index) => index.index.to_string()
+                };
+                field_entries.push(quote! { (#key.to_string(), #json_ident::to_json(#binding)) });
+                field_vals.push(quote! { #member : #json_ident::from_json(field(#key)?)? });
+            }
+        }
+
+        to_json_arms.push(quote! {
+            #(#cfgs)*
+            Self::#variant_ident { #(#members : #bindings),* } => {
+                entries.push(("fields".to_string(), #value_ident::Object(vec![#(#field_entries),*])));
+                #info_entries
+            },
+        });
+
+        let long_name = variant_ident.to_string();
+        let name_pat = match short_name_of(short_names, variant_ident).filter(|short_ident| *short_ident != variant_ident) {
+            Some(short_ident) => {
+                let short_name = short_ident.to_string();
+                quote! { #long_name | #short_name }
+            },
+            None => quote! { #long_name }
+        };
+        from_json_arms.push(quote! {
+            #(#cfgs)*
+            #name_pat => Ok(Self::#variant_ident { #(#field_vals),* }),
+        });
+    }
+
+    quote! {
+        #[doc = #value_doc]
+        #[derive(Debug, Clone, PartialEq)]
+        #vis enum #value_ident {
+            Null,
+            Bool(bool),
+            /// Kept as written, so integers of any size round-trip.
+            Number(String),
+            String(String),
+            Array(Vec<#value_ident>),
+            Object(Vec<(String, #value_ident)>),
+        }
+
+        impl #value_ident {
+            pub fn get(&self, key : &str) -> Option<&#value_ident> {
+                match self {
+                    #value_ident::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+                    _ => None
+                }
+            }
+
+            pub fn write(&self, w : &mut impl std::io::Write) -> std::io::Result<()> {
+                match self {
+                    #value_ident::Null => w.write_all(b"null"),
+                    #value_ident::Bool(b) => write!(w, "{}", b),
+                    #value_ident::Number(n) => w.write_all(n.as_bytes()),
+                    #value_ident::String(s) => #value_ident::write_str(s, w),
+                    #value_ident::Array(items) => {
+                        w.write_all(b"[")?;
+                        for (i, item) in items.iter().enumerate() {
+                            if i > 0 {
+                                w.write_all(b",")?;
+                            }
+                            item.write(w)?;
+                        }
+                        w.write_all(b"]")
+                    },
+                    #value_ident::Object(entries) => {
+                        w.write_all(b"{")?;
+                        for (i, (k, v)) in entries.iter().enumerate() {
+                            if i > 0 {
+                                w.write_all(b",")?;
+                            }
+                            #value_ident::write_str(k, w)?;
+                            w.write_all(b":")?;
+                            v.write(w)?;
+                        }
+                        w.write_all(b"}")
+                    }
+                }
+            }
+
+            fn write_str(s : &str, w : &mut impl std::io::Write) -> std::io::Result<()> {
+                w.write_all(b"\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => w.write_all(b"\\\"")?,
+                        '\\' => w.write_all(b"\\\\")?,
+                        '\n' => w.write_all(b"\\n")?,
+                        '\r' => w.write_all(b"\\r")?,
+                        '\t' => w.write_all(b"\\t")?,
+                        c if (c as u32) < 0x20 => write!(w, "\\u{:04x}", c as u32)?,
+                        c => write!(w, "{}", c)?,
+                    }
+                }
+                w.write_all(b"\"")
+            }
+
+            pub fn parse(s : &str) -> Result<#value_ident, String> {
+                fn skip_ws(s : &[u8], pos : &mut usize) {
+                    while s.get(*pos).map_or(false, |b| b.is_ascii_whitespace()) {
+                        *pos += 1;
+                    }
+                }
+
+                fn expect(s : &[u8], pos : &mut usize, byte : u8) -> Result<(), String> {
+                    skip_ws(s, pos);
+                    if s.get(*pos) == Some(&byte) {
+                        *pos += 1;
+                        Ok(())
+                    } else {
+                        Err(format!("expected `{}` at byte {}", byte as char, *pos))
+                    }
+                }
+
+                fn parse_hex4(s : &[u8], pos : &mut usize) -> Result<u32, String> {
+                    let hex = s.get(*pos..*pos + 4)
+                               .and_then(|hex| std::str::from_utf8(hex).ok())
+                               .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                               .ok_or_else(|| format!("bad `\\u` escape at byte {}", *pos))?;
+                    *pos += 4;
+                    Ok(hex)
+                }
+
+                fn parse_str(s : &[u8], pos : &mut usize) -> Result<String, String> {
+                    expect(s, pos, b'"')?;
+                    let mut bytes = Vec::new();
+                    loop {
+                        match s.get(*pos) {
+                            None => return Err("unterminated string".to_string()),
+                            Some(b'"') => {
+                                *pos += 1;
+                                break
+                            },
+                            Some(b'\\') => {
+                                let escaped = *s.get(*pos + 1).ok_or_else(|| "unterminated string".to_string())?;
+                                *pos += 2;
+                                let c = match escaped {
+                                    b'"' => '"',
+                                    b'\\' => '\\',
+                                    b'/' => '/',
+                                    b'n' => '\n',
+                                    b'r' => '\r',
+                                    b't' => '\t',
+                                    b'b' => '\u{8}',
+                                    b'f' => '\u{c}',
+                                    b'u' => {
+                                        let mut code = parse_hex4(s, pos)?;
+                                        if (0xd800..0xdc00).contains(&code) && s.get(*pos..*pos + 2) == Some(b"\\u") {
+                                            *pos += 2;
+                                            let low = parse_hex4(s, pos)?;
+                                            code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                                        }
+                                        std::char::from_u32(code).ok_or_else(|| format!("bad `\\u` escape before byte {}", *pos))?
+                                    },
+                                    _ => return Err(format!("bad escape at byte {}", *pos - 1))
+                                };
+                                bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                            },
+                            Some(&b) => {
+                                bytes.push(b);
+                                *pos += 1;
+                            }
+                        }
+                    }
+                    String::from_utf8(bytes).map_err(|e| e.to_string())
+                }
+
+                fn parse_value(s : &[u8], pos : &mut usize) -> Result<#value_ident, String> {
+                    skip_ws(s, pos);
+                    match s.get(*pos) {
+                        Some(b'n') if s[*pos..].starts_with(b"null") => {
+                            *pos += 4;
+                            Ok(#value_ident::Null)
+                        },
+                        Some(b't') if s[*pos..].starts_with(b"true") => {
+                            *pos += 4;
+                            Ok(#value_ident::Bool(true))
+                        },
+                        Some(b'f') if s[*pos..].starts_with(b"false") => {
+                            *pos += 5;
+                            Ok(#value_ident::Bool(false))
+                        },
+                        Some(b'"') => parse_str(s, pos).map(#value_ident::String),
+                        Some(b'[') => {
+                            *pos += 1;
+                            let mut items = Vec::new();
+                            skip_ws(s, pos);
+                            if s.get(*pos) == Some(&b']') {
+                                *pos += 1;
+                                return Ok(#value_ident::Array(items))
+                            }
+                            loop {
+                                items.push(parse_value(s, pos)?);
+                                skip_ws(s, pos);
+                                match s.get(*pos) {
+                                    Some(b',') => *pos += 1,
+                                    Some(b']') => {
+                                        *pos += 1;
+                                        return Ok(#value_ident::Array(items))
+                                    },
+                                    _ => return Err(format!("expected `,` or `]` at byte {}", *pos))
+                                }
+                            }
+                        },
+                        Some(b'{') => {
+                            *pos += 1;
+                            let mut entries = Vec::new();
+                            skip_ws(s, pos);
+                            if s.get(*pos) == Some(&b'}') {
+                                *pos += 1;
+                                return Ok(#value_ident::Object(entries))
+                            }
+                            loop {
+                                let key = parse_str(s, pos)?;
+                                expect(s, pos, b':')?;
+                                entries.push((key, parse_value(s, pos)?));
+                                skip_ws(s, pos);
+                                match s.get(*pos) {
+                                    Some(b',') => *pos += 1,
+                                    Some(b'}') => {
+                                        *pos += 1;
+                                        return Ok(#value_ident::Object(entries))
+                                    },
+                                    _ => return Err(format!("expected `,` or `}}` at byte {}", *pos))
+                                }
+                            }
+                        },
+                        Some(b) if *b == b'-' || b.is_ascii_digit() => {
+                            let start = *pos;
+                            while s.get(*pos).map_or(false, |b| b.is_ascii_digit() || b"+-.eE".contains(b)) {
+                                *pos += 1;
+                            }
+                            Ok(#value_ident::Number(String::from_utf8_lossy(&s[start..*pos]).into_owned()))
+                        },
+                        _ => Err(format!("unexpected input at byte {}", *pos))
+                    }
+                }
+
+                let s = s.as_bytes();
+                let mut pos = 0;
+                let value = parse_value(s, &mut pos)?;
+                skip_ws(s, &mut pos);
+                if pos == s.len() {
+                    Ok(value)
+                } else {
+                    Err(format!("trailing input at byte {}", pos))
+                }
+            }
+        }
+
+        #[doc = #json_doc]
+        #vis trait #json_ident : Sized {
+            fn to_json(&self) -> #value_ident;
+            fn from_json(value : &#value_ident) -> Result<Self, String>;
+        }
+
+        #(
+            impl #json_ident for #uints {
+                fn to_json(&self) -> #value_ident {
+                    #value_ident::Number(self.to_string())
+                }
+
+                fn from_json(value : &#value_ident) -> Result<Self, String> {
+                    match value {
+                        #value_ident::Number(n) => n.parse().map_err(|e| format!("bad number `{}` : {}", n, e)),
+                        _ => Err(format!("expected a number, got {:?}", value))
+                    }
+                }
+            }
+        )*
+
+        impl #json_ident for bool {
+            fn to_json(&self) -> #value_ident {
+                #value_ident::Bool(*self)
+            }
+
+            fn from_json(value : &#value_ident) -> Result<Self, String> {
+                match value {
+                    #value_ident::Bool(b) => Ok(*b),
+                    _ => Err(format!("expected a bool, got {:?}", value))
+                }
+            }
+        }
+
+        impl #json_ident for String {
+            fn to_json(&self) -> #value_ident {
+                #value_ident::String(self.clone())
+            }
+
+            fn from_json(value : &#value_ident) -> Result<Self, String> {
+                match value {
+                    #value_ident::String(s) => Ok(s.clone()),
+                    _ => Err(format!("expected a string, got {:?}", value))
+                }
+            }
+        }
+
+        impl<T : #json_ident> #json_ident for Option<T> {
+            fn to_json(&self) -> #value_ident {
+                match self {
+                    Some(t) => t.to_json(),
+                    None => #value_ident::Null
+                }
+            }
+
+            fn from_json(value : &#value_ident) -> Result<Self, String> {
+                match value {
+                    #value_ident::Null => Ok(None),
+                    _ => T::from_json(value).map(Some)
+                }
+            }
+        }
+
+        impl<T : #json_ident> #json_ident for Vec<T> {
+            fn to_json(&self) -> #value_ident {
+                #value_ident::Array(self.iter().map(#json_ident::to_json).collect())
+            }
+
+            fn from_json(value : &#value_ident) -> Result<Self, String> {
+                match value {
+                    #value_ident::Array(items) => items.iter().map(T::from_json).collect(),
+                    _ => Err(format!("expected an array, got {:?}", value))
+                }
+            }
+        }
+
+        impl #impl_generics #step_ident #ty_generics #where_clause {
+            pub fn to_json(&self) -> #value_ident {
+                let mut entries = vec![
+                    ("kind".to_string(), #value_ident::String(self.get_step_name_string().to_string())),
+                    ("short".to_string(), #value_ident::String(self.get_step_name_string_short().to_string())),
+                ];
+                match self {
+                    #(#to_json_arms)*
+                }
+                #value_ident::Object(entries)
+            }
+
+            /// Writes the step as one line of JSON.
+            pub fn write_json(&self, w : &mut impl std::io::Write) -> std::io::Result<()> {
+                self.to_json().write(w)?;
+                w.write_all(b"\n")
+            }
+
+            // Variants without fields (or info) leave `field` (or `info`) unused.
+            #[allow(unused_variables)]
+            pub fn from_json(value : &#value_ident) -> Result<Self, String> {
+                let kind = match value.get("kind") {
+                    Some(#value_ident::String(kind)) => kind.as_str(),
+                    _ => return Err("step has no `kind`".to_string())
+                };
+                let fields = value.get("fields");
+                let field = |name : &str| {
+                    fields.and_then(|fields| fields.get(name))
+                          .ok_or_else(|| format!("{} step has no field `{}`", kind, name))
+                };
+                let info = value.get("info").unwrap_or(value);
+                match kind {
+                    #(#from_json_arms)*
+                    _ => Err(format!("unknown step kind `{}`", kind))
+                }
+            }
+
+            /// Reads a step back from a line written by `write_json`.
+            pub fn read_json(line : &str) -> Result<Self, String> {
+                Self::from_json(&#value_ident::parse(line)?)
+            }
+
+            /// Reads a JSON Lines trace, skipping blank lines.
+            pub fn read_json_lines(r : impl std::io::BufRead) -> impl Iterator<Item = std::io::Result<Self>> {
+                r.lines()
+                 .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+                 .map(|line| line.and_then(|line| {
+                     Self::read_json(&line).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+                 }))
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::{ HashMap, HashSet };
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{ quote, format_ident };
+use syn::{ parse::Result,
+           Ident };
+
+use crate::helpers::Errors;
+use crate::step_derive::{ cfg_attrs, short_name_of };
+
+// `Step` gets a fieldless `StepKind` (named after the step enum) with one
+// variant per step variant, for consumers that only care about what kind
+// of step they're looking at : `Step::kind()`, `StepKind::ALL`, the long
+// and short names, the numeric ids, and `FromStr` taking either name.
+pub fn mk_step_kind(base_enum : &syn::ItemEnum, short_names : &[(Ident, Ident)], ids : &HashMap<Ident, u16>) -> TokenStream2 {
+    let step_ident = &base_enum.ident;
+    let kind_ident = kind_ident(step_ident);
+    let vis = &base_enum.vis;
+    let (impl_generics, ty_generics, where_clause) = base_enum.generics.split_for_impl();
+    let kind_doc = format!("The kind of a [`{}`], without its fields.", step_ident);
+
+    let variants = base_enum.variants.iter().map(|v| {
+        let variant_ident = &v.ident;
+        let cfgs = cfg_attrs(v);
+        quote! { #(#cfgs)* #variant_ident }
+    }).collect::<Vec<TokenStream2>>();
+
+    let all_kinds = base_enum.variants.iter().map(|v| {
+        let variant_ident = &v.ident;
+        let cfgs = cfg_attrs(v);
+        quote! { #(#cfgs)* #kind_ident::#variant_ident }
+    }).collect::<Vec<TokenStream2>>();
+
+    let kind_arms = base_enum.variants.iter().map(|v| {
+        let variant_ident = &v.ident;
+        let cfgs = cfg_attrs(v);
+        quote! { #(#cfgs)* Self::#variant_ident { .. } => #kind_ident::#variant_ident, }
+    }).collect::<Vec<TokenStream2>>();
+
+    let name_arms = base_enum.variants.iter().map(|v| {
+        let variant_ident = &v.ident;
+        let cfgs = cfg_attrs(v);
+        quote! { #(#cfgs)* Self::#variant_ident => stringify!(#variant_ident), }
+    }).collect::<Vec<TokenStream2>>();
+
+    let short_name_arms = base_enum.variants.iter().map(|v| {
+        let variant_ident = &v.ident;
+        let short_ident = short_name_of(short_names, variant_ident).unwrap_or(variant_ident);
+        let cfgs = cfg_attrs(v);
+        quote! { #(#cfgs)* Self::#variant_ident => stringify!(#short_ident), }
+    }).collect::<Vec<TokenStream2>>();
+
+    let id_arms = base_enum.variants.iter().filter_map(|v| {
+        let variant_ident = &v.ident;
+        let id = ids.get(variant_ident)?;
+        let cfgs = cfg_attrs(v);
+        Some(quote! { #(#cfgs)* Self::#variant_ident => #id, })
+    }).collect::<Vec<TokenStream2>>();
+
+    let from_id_arms = base_enum.variants.iter().filter_map(|v| {
+        let variant_ident = &v.ident;
+        let id = ids.get(variant_ident)?;
+        let cfgs = cfg_attrs(v);
+        Some(quote! { #(#cfgs)* #id => Some(#kind_ident::#variant_ident), })
+    }).collect::<Vec<TokenStream2>>();
+
+    // Variants without a `#[short]` have their long name as their short
+    // name; they only get the one arm.
+    let from_str_arms = base_enum.variants.iter().map(|v| {
+        let variant_ident = &v.ident;
+        let cfgs = cfg_attrs(v);
+        match short_name_of(short_names, variant_ident).filter(|short_ident| *short_ident != variant_ident) {
+            Some(short_ident) => quote! { #(#cfgs)* stringify!(#variant_ident) | stringify!(#short_ident) => Ok(#kind_ident::#variant_ident), },
+            None => quote! { #(#cfgs)* stringify!(#variant_ident) => Ok(#kind_ident::#variant_ident), }
+        }
+    }).collect::<Vec<TokenStream2>>();
+
+    let err_msg = format!("unknown {} `{{}}`", kind_ident);
+
+    quote! {
+        #[doc = #kind_doc]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #vis enum #kind_ident {
+            #(#variants,)*
+        }
+
+        impl #kind_ident {
+            pub const ALL : &'static [#kind_ident] = &[
+                #(#all_kinds,)*
+            ];
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    #(#name_arms)*
+                }
+            }
+
+            pub fn short_name(self) -> &'static str {
+                match self {
+                    #(#short_name_arms)*
+                }
+            }
+
+            pub fn id(self) -> u16 {
+                match self {
+                    #(#id_arms)*
+                }
+            }
+
+            pub fn from_id(id : u16) -> Option<#kind_ident> {
+                match id {
+                    #(#from_id_arms)*
+                    _ => None
+                }
+            }
+        }
+
+        impl std::str::FromStr for #kind_ident {
+            type Err = String;
+
+            fn from_str(s : &str) -> Result<Self, Self::Err> {
+                match s {
+                    #(#from_str_arms)*
+                    _ => Err(format!(#err_msg, s))
+                }
+            }
+        }
+
+        impl #impl_generics #step_ident #ty_generics #where_clause {
+            pub fn kind(&self) -> #kind_ident {
+                match self {
+                    #(#kind_arms)*
+                }
+            }
+
+            pub fn id(&self) -> u16 {
+                self.kind().id()
+            }
+        }
+    }
+}
+
+// `Step` => `StepKind`
+pub fn kind_ident(step_ident : &Ident) -> Ident {
+    format_ident!("{}Kind", step_ident)
+}
+
+// Numeric ids for the variants, for trace formats that have to survive
+// variants being reordered or added. `#[step_id = N]` fixes a variant's id;
+// the others get the lowest ids not already taken, in declaration order,
+// unless `explicit_ids` is set, in which case every variant needs one.
+// Strips the `#[step_id]` attributes.
+pub fn collect_step_ids(base_enum : &mut syn::ItemEnum, explicit_ids : bool) -> Result<HashMap<Ident, u16>> {
+    let mut errors = Errors::default();
+    let mut ids = HashMap::<Ident, u16>::new();
+    let mut taken = HashSet::<u16>::new();
+    let mut unassigned = Vec::<Ident>::new();
+
+    for variant in base_enum.variants.iter_mut() {
+        let id_attrs = variant.attrs.iter().filter(|attr| attr.path.is_ident("step_id")).collect::<Vec<&syn::Attribute>>();
+        match id_attrs.as_slice() {
+            [] if explicit_ids => {
+                errors.push(syn::Error::new(variant.ident.span(), format!("`{}` needs a `#[step_id = N]`, since the \
+                                                                           is_step attribute has `explicit_ids`", variant.ident)));
+            },
+            [] => unassigned.push(variant.ident.clone()),
+            [id_attr] => {
+                if let Some(id) = errors.take(parse_step_id_attr(id_attr)) {
+                    if !taken.insert(id) {
+                        errors.push(syn::Error::new_spanned(id_attr, format!("step id {} is used more than once", id)));
+                    }
+                    ids.insert(variant.ident.clone(), id);
+                }
+            },
+            [_, extra, ..] => errors.push(syn::Error::new_spanned(extra, "a variant can only have one `#[step_id]`"))
+        }
+        variant.attrs.retain(|attr| !attr.path.is_ident("step_id"));
+    }
+
+    let mut free_ids = (0..=u16::MAX).filter(|id| !taken.contains(id));
+    for variant_ident in unassigned {
+        match free_ids.next() {
+            Some(id) => { ids.insert(variant_ident, id); },
+            None => errors.push(syn::Error::new(variant_ident.span(), "ran out of step ids"))
+        }
+    }
+
+    errors.finish()?;
+    Ok(ids)
+}
+
+// `#[step_id = 3]` => `3`
+fn parse_step_id_attr(id_attr : &syn::Attribute) -> Result<u16> {
+    match id_attr.parse_meta()? {
+        syn::Meta::NameValue(syn::MetaNameValue { lit : syn::Lit::Int(lit_int), .. }) => lit_int.base10_parse::<u16>(),
+        _ => Err(syn::Error::new_spanned(id_attr, "`step_id` attribute must be given a `u16`, ie `#[step_id = 3]`"))
+    }
+}
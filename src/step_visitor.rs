@@ -0,0 +1,112 @@
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{ quote, format_ident };
+use syn::{ Field, Ident, Variant };
+
+use crate::helpers::{ hygienic_ident, snake_case_name };
+use crate::paths::TracePaths;
+use crate::step_derive::{ cfg_attrs, info_member };
+
+// `StepVisitor` and `StepVisitorMut` (named after the step enum) : one
+// `visit_<snake_name>` method per variant with a default no-op body, taking
+// the variant's info first and then its other fields, and
+// `Step::accept`/`Step::accept_mut` to dispatch a step to the right one.
+pub fn mk_step_visitors(base_enum : &syn::ItemEnum, paths : &TracePaths) -> TokenStream2 {
+    let visitor = mk_step_visitor(base_enum, paths, false);
+    let visitor_mut = mk_step_visitor(base_enum, paths, true);
+    quote! {
+        #visitor
+        #visitor_mut
+    }
+}
+
+// A field as the visitor and checker methods see it : the member it's read
+// from, the name of the method parameter it's passed as, and the hygienic
+// name it's bound to in the generated match arms, so fields can't shadow the
+// generated code's own locals.
+pub struct VisitedField<'v> {
+    pub member : syn::Member,
+    pub name : Ident,
+    pub binding : Ident,
+    pub field : &'v Field,
+}
+
+// A variant's fields, info first. Named fields keep their names as parameter
+// names, the info is `info`, and other tuple fields are `a0, a1, ..`.
+pub fn visited_fields<'v>(v : &'v Variant, paths : &TracePaths) -> Vec<VisitedField<'v>> {
+    let info_member = info_member(v, paths);
+    let mut fields = v.fields.iter().enumerate().map(|(idx, field)| {
+        let member = match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(syn::Index::from(idx))
+        };
+        let name = match &field.ident {
+            Some(ident) => ident.clone(),
+            None if Some(&member) == info_member.as_ref() => format_ident!("info"),
+            None => format_ident!("a{}", idx)
+        };
+        let binding = hygienic_ident(&format!("f{}", idx));
+        VisitedField { member, name, binding, field }
+    }).collect::<Vec<VisitedField>>();
+
+    if let Some(info_pos) = fields.iter().position(|f| Some(&f.member) == info_member.as_ref()) {
+        let info_field = fields.remove(info_pos);
+        fields.insert(0, info_field);
+    }
+    fields
+}
+
+fn mk_step_visitor(base_enum : &syn::ItemEnum, paths : &TracePaths, mutable : bool) -> TokenStream2 {
+    let step_ident = &base_enum.ident;
+    let vis = &base_enum.vis;
+    let (impl_generics, ty_generics, where_clause) = base_enum.generics.split_for_impl();
+    let (trait_ident, accept_ident, ref_kind) = if mutable {
+        (format_ident!("{}VisitorMut", step_ident), format_ident!("accept_mut"), quote!(&mut))
+    } else {
+        (format_ident!("{}Visitor", step_ident), format_ident!("accept"), quote!(&))
+    };
+    let visitor = hygienic_ident("visitor");
+    let trait_doc = format!("Visits each kind of [`{}`] with its fields{}.", step_ident, if mutable { ", mutably" } else { "" });
+
+    let methods = base_enum.variants.iter().map(|v| {
+        let method_ident = format_ident!("visit_{}", snake_case_name(&v.ident));
+        let cfgs = cfg_attrs(v);
+        let params = visited_fields(v, paths).into_iter().map(|f| {
+            let name = &f.name;
+            let ty = &f.field.ty;
+            quote! { #name : #ref_kind #ty }
+        }).collect::<Vec<TokenStream2>>();
+        quote! {
+            #(#cfgs)*
+            #[allow(unused_variables, clippy::too_many_arguments, clippy::ptr_arg)]
+            fn #method_ident(&mut self, #(#params),*) {}
+        }
+    }).collect::<Vec<TokenStream2>>();
+
+    let accept_arms = base_enum.variants.iter().map(|v| {
+        let variant_ident = &v.ident;
+        let method_ident = format_ident!("visit_{}", snake_case_name(variant_ident));
+        let cfgs = cfg_attrs(v);
+        let fields = visited_fields(v, paths);
+        let field_pats = fields.iter().map(|VisitedField { member, binding, .. }| quote!(#member : #binding));
+        let bindings = fields.iter().map(|f| &f.binding);
+        quote! {
+            #(#cfgs)*
+            Self::#variant_ident { #(#field_pats),* } => #visitor.#method_ident(#(#bindings),*),
+        }
+    }).collect::<Vec<TokenStream2>>();
+
+    quote! {
+        #[doc = #trait_doc]
+        #vis trait #trait_ident #impl_generics #where_clause {
+            #(#methods)*
+        }
+
+        impl #impl_generics #step_ident #ty_generics #where_clause {
+            pub fn #accept_ident(#ref_kind self, #visitor : &mut impl #trait_ident #ty_generics) {
+                match self {
+                    #(#accept_arms)*
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+// `#[trace]` on `async fn`s and on fns returning `async` blocks, with
+// futures that share a manager polled in turns.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ Context, Poll, Wake, Waker };
+use parking_lot::RwLock;
+use nanoda_macros::trace;
+
+mod trace;
+use crate::trace::{ Expr, Mgr, Step, TraceMgr, VecTracer };
+
+fn expr(s : &str) -> Expr {
+    Expr(s.to_string())
+}
+
+// Pending on the first poll, ready on the second.
+struct YieldOnce(bool);
+
+impl Future for YieldOnce {
+    type Output = ();
+    fn poll(mut self : Pin<&mut Self>, cx : &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self : Arc<Self>) {}
+}
+
+pub struct Tc {
+    pub mgr : RwLock<Mgr>,
+}
+
+impl Tc {
+    #[trace(self.mgr, Whnf(expr(a)))]
+    pub async fn whnf(&self, a : &str) -> Result<usize, String> {
+        YieldOnce(false).await;
+        if a.is_empty() {
+            return Err("empty".to_string())
+        }
+        let n : usize = a.parse().map_err(|_| format!("{} isn't a number", a))?;
+        Ok(n)
+    }
+
+    // Written out as a fn returning an `async` block on purpose.
+    #[trace(self.mgr, EqCore(expr(a), expr(a)))]
+    #[allow(clippy::manual_async_fn)]
+    pub fn eq<'a>(&'a self, a : &'a str) -> impl Future<Output = usize> + 'a {
+        async move {
+            let n = self.whnf(a).await.unwrap();
+            YieldOnce(false).await;
+            n
+        }
+    }
+
+    #[trace(self.mgr, Whnf(expr(a)))]
+    pub async fn reject(&self, a : &str) -> usize {
+        YieldOnce(false).await;
+        panic!("rejected {}", a)
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn interleaved_tasks_keep_their_own_stacks() {
+    let tc = Tc { mgr : RwLock::new(TraceMgr::new(VecTracer::default())) };
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+    let mut fst = Box::pin(tc.eq("1"));
+    let mut snd = Box::pin(tc.eq("2"));
+    let (mut fst_done, mut snd_done) = (None, None);
+
+    while fst_done.is_none() || snd_done.is_none() {
+        if fst_done.is_none() {
+            if let Poll::Ready(n) = fst.as_mut().poll(&mut cx) {
+                fst_done = Some(n)
+            }
+        }
+        // Between polls neither task's steps are on the manager's stack.
+        assert_eq!(tc.mgr.read().stack_len(), 0);
+        if snd_done.is_none() {
+            if let Poll::Ready(n) = snd.as_mut().poll(&mut cx) {
+                snd_done = Some(n)
+            }
+        }
+        assert_eq!(tc.mgr.read().stack_len(), 0);
+    }
+
+    assert_eq!((fst_done, snd_done), (Some(1), Some(2)));
+    let mgr = tc.mgr.read();
+    let traced = mgr.traced();
+    assert_eq!(traced.len(), 4);
+    // Each `eq` step has only its own task's `whnf` step as a child.
+    for (idx, step) in traced.iter().enumerate() {
+        if let Step::EqCore { info, l, .. } = step {
+            assert_eq!(info.children.len(), 1);
+            match &traced[info.children[0]] {
+                Step::Whnf { e, .. } => assert_eq!(mgr.item_storage[*e], mgr.item_storage[*l]),
+                child => panic!("step {} has an unexpected child {:?}", idx, child)
+            }
+        }
+        assert!(!step.get_step_info().unwrap().aborted);
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn unwinding_future_aborts_its_step() {
+    let tc = Tc { mgr : RwLock::new(TraceMgr::new(VecTracer::default())) };
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+    let mut rejected = Box::pin(tc.reject("x"));
+
+    assert!(rejected.as_mut().poll(&mut cx).is_pending());
+    let unwound = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| rejected.as_mut().poll(&mut cx)));
+    assert!(unwound.is_err());
+    assert_eq!(tc.mgr.read().stack_len(), 0);
+    assert!(tc.mgr.read().traced()[0].get_step_info().unwrap().aborted);
+
+    let mut whnf = Box::pin(tc.whnf(""));
+    loop {
+        if let Poll::Ready(result) = whnf.as_mut().poll(&mut cx) {
+            assert!(result.is_err());
+            break
+        }
+    }
+    assert_eq!(tc.mgr.read().traced().len(), 2);
+    assert_eq!(tc.mgr.read().stack_len(), 0);
+}
@@ -0,0 +1,277 @@
+// What `#[is_step]` generates for the mock `Step` enum : constructors,
+// visitors, the checker, the binary codec, JSON Lines and `Display`.
+use nanoda_macros::trace;
+
+mod trace;
+use crate::trace::{ Expr, ItemIdx, ItemStorage, Mgr, Step, StepChecker, StepCodec, StepInfo, StepKind, StepVisitor,
+                    StepVisitorMut, TraceMgr, VecTracer };
+
+fn expr(s : &str) -> Expr {
+    Expr(s.to_string())
+}
+
+#[trace(mgr, EqCore(expr(a), expr(b)), access = direct)]
+fn eq(mgr : &mut Mgr, a : &str, b : &str) -> bool {
+    a == b && whnf(mgr, a)
+}
+
+#[trace(mgr, Whnf(expr(a)), access = direct)]
+fn whnf(mgr : &mut Mgr, a : &str) -> bool {
+    !a.is_empty()
+}
+
+#[trace(mgr, Shadow(1usize, 2usize, expr(a), 3usize), access = direct)]
+fn shadow(mgr : &mut Mgr, a : &str) -> bool {
+    a.is_empty()
+}
+
+// A manager whose tracer holds one step of each kind; the `Fail` and `Done`
+// steps are built directly.
+fn traced() -> Mgr {
+    let mut mgr = TraceMgr::new(VecTracer::default());
+    assert!(eq(&mut mgr, "x", "x"));
+    assert!(!shadow(&mut mgr, "y"));
+    let fail = mgr.new_fail(0usize);
+    mgr.tracer.0.push(fail);
+    let done = mgr.new_done();
+    mgr.tracer.0.push(done);
+    mgr
+}
+
+#[test]
+fn constructors() {
+    let mut mgr = TraceMgr::new(VecTracer::default());
+    match mgr.new_eq_core(expr("l"), expr("r")) {
+        Step::EqCore { info, l, r } => {
+            assert_eq!(info.safety_idx, 1);
+            assert_eq!((&mgr.item_storage[l], &mgr.item_storage[r]), (&expr("l"), &expr("r")));
+        },
+        step => panic!("unexpected step {:?}", step)
+    }
+    match mgr.new_fail(true) {
+        Step::Fail(info, a1) => {
+            assert_eq!(info.safety_idx, 2);
+            assert_eq!(mgr.item_storage[a1], expr("true"));
+        },
+        step => panic!("unexpected step {:?}", step)
+    }
+    assert!(mgr.new_done().get_step_info().is_none());
+    match mgr.new_shadow(1usize, 2usize, expr("e"), 3usize) {
+        Step::Shadow { items, result, .. } => {
+            assert_eq!(mgr.item_storage[items], expr("e"));
+            assert_eq!(mgr.item_storage[result], expr("3"));
+        },
+        step => panic!("unexpected step {:?}", step)
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn names_and_kinds() {
+    let mgr = traced();
+    let names = mgr.traced().iter().map(|step| step.get_step_name_string_short()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["W", "EC", "Shadow", "Fail", "Done"]);
+    assert_eq!(mgr.traced()[1].kind(), StepKind::EqCore);
+    assert_eq!(mgr.traced()[1].get_step_name_string(), "EqCore");
+}
+
+#[derive(Default)]
+struct Collect(Vec<String>);
+
+impl StepVisitor for Collect {
+    fn visit_eq_core(&mut self, info : &StepInfo, l : &ItemIdx, r : &ItemIdx) {
+        self.0.push(format!("eq_core {} {} {}", info.safety_idx, l.0, r.0))
+    }
+
+    fn visit_fail(&mut self, _info : &StepInfo, a1 : &ItemIdx) {
+        self.0.push(format!("fail {}", a1.0))
+    }
+
+    fn visit_shadow(&mut self, _info : &StepInfo, visitor : &ItemIdx, checker : &ItemIdx, items : &ItemIdx, result : &ItemIdx) {
+        self.0.push(format!("shadow {} {} {} {}", visitor.0, checker.0, items.0, result.0))
+    }
+
+    fn visit_done(&mut self) {
+        self.0.push("done".to_string())
+    }
+}
+
+struct MarkAborted;
+
+impl StepVisitorMut for MarkAborted {
+    fn visit_whnf(&mut self, info : &mut StepInfo, _e : &mut ItemIdx) {
+        info.aborted = true
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn visitors() {
+    let mut mgr = traced();
+    let mut collect = Collect::default();
+    for step in mgr.traced() {
+        step.accept(&mut collect);
+    }
+    assert_eq!(collect.0, vec![
+        "eq_core 1 0 1".to_string(),
+        "shadow 5 6 7 8".to_string(),
+        "fail 10".to_string(),
+        "done".to_string(),
+    ]);
+
+    for step in mgr.tracer.0.iter_mut() {
+        step.accept_mut(&mut MarkAborted);
+    }
+    assert!(mgr.traced()[0].get_step_info().unwrap().aborted);
+    assert!(!mgr.traced()[1].get_step_info().unwrap().aborted);
+}
+
+// Checks that each step's result is what replaying it gives.
+struct Replay;
+
+impl StepChecker for Replay {
+    type Items = ItemStorage;
+    type ResultItem = Expr;
+    type Error = String;
+
+    fn result_item<'i>(&self, items : &'i ItemStorage, step : &Step) -> Option<&'i Expr> {
+        step.get_step_info().and_then(|info| info.result).map(|result| &items[result])
+    }
+
+    fn check_eq_core(&mut self, _items : &ItemStorage, info : &StepInfo, l : &Expr, r : &Expr, result : Option<&Expr>) -> Result<(), String> {
+        if info.children.is_empty() {
+            return Err("an eq_core step has a whnf child".to_string())
+        }
+        match result {
+            Some(result) if *result == expr(&(l == r).to_string()) => Ok(()),
+            _ => Err(format!("{:?} ≡ {:?} doesn't give {:?}", l, r, result))
+        }
+    }
+
+    fn check_whnf(&mut self, _items : &ItemStorage, _info : &StepInfo, e : &Expr, result : Option<&Expr>) -> Result<(), String> {
+        match result {
+            Some(result) if *result == expr(&(!e.0.is_empty()).to_string()) => Ok(()),
+            _ => Err(format!("whnf {:?} doesn't give {:?}", e, result))
+        }
+    }
+
+    fn check_fail(&mut self, _items : &ItemStorage, _info : &StepInfo, _a1 : &ItemIdx, _result : Option<&Expr>) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn check_shadow(&mut self,
+                    items : &ItemStorage,
+                    _info : &StepInfo,
+                    visitor : &ItemIdx,
+                    checker : &ItemIdx,
+                    item : &Expr,
+                    result : &ItemIdx,
+                    step_result : Option<&Expr>) -> Result<(), String> {
+        assert_eq!(items[*visitor], expr("1"));
+        assert_eq!(items[*checker], expr("2"));
+        assert_eq!(items[*result], expr("3"));
+        match step_result {
+            Some(step_result) if *step_result == expr(&item.0.is_empty().to_string()) => Ok(()),
+            _ => Err(format!("shadow {:?} doesn't give {:?}", item, step_result))
+        }
+    }
+
+    fn check_done(&mut self, _items : &ItemStorage, _result : Option<&Expr>) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn checker() {
+    let mut mgr = traced();
+    Step::check_trace(mgr.traced(), &mgr.item_storage, &mut Replay).unwrap();
+
+    // Point the `whnf` step's result at the wrong item.
+    *mgr.tracer.0[0].get_mut_result() = Some(ItemIdx(1));
+    let failure = Step::check_trace(mgr.traced(), &mgr.item_storage, &mut Replay).unwrap_err();
+    assert_eq!((failure.index, failure.kind, failure.short_name), (0, StepKind::Whnf, "W"));
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn codec() {
+    let mgr = traced();
+    let mut bytes = Vec::new();
+    for step in mgr.traced() {
+        step.encode(&mut bytes).unwrap();
+    }
+    let mut reader = bytes.as_slice();
+    for step in mgr.traced() {
+        assert_eq!(Step::decode(&mut reader).unwrap(), *step);
+    }
+    assert!(reader.is_empty());
+}
+
+#[test]
+fn codec_varints() {
+    let mut bytes = Vec::new();
+    u64::MAX.encode_field(&mut bytes).unwrap();
+    assert_eq!(bytes.len(), 10);
+    assert_eq!(u64::decode_field(&mut bytes.as_slice()).unwrap(), u64::MAX);
+    // A tenth byte with more than the top bit set overflows a `u64`.
+    for last in &[0x02u8, 0x7f, 0xff] {
+        let mut overlong = vec![0xffu8; 9];
+        overlong.extend(&[*last, 0x00]);
+        let e = u64::decode_field(&mut overlong.as_slice()).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn json_lines() {
+    let mgr = traced();
+    let mut lines = Vec::new();
+    for step in mgr.traced() {
+        step.write_json(&mut lines).unwrap();
+    }
+    let read = Step::read_json_lines(lines.as_slice()).collect::<std::io::Result<Vec<Step>>>().unwrap();
+    assert_eq!(read, mgr.traced());
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn display() {
+    let mgr = traced();
+    let shown = mgr.traced().iter().map(|step| step.to_string()).collect::<Vec<_>>();
+    assert_eq!(shown, vec!["Whnf", "#0 ≡ #1", "Shadow", "failed on #10", "Done"]);
+    assert_eq!(mgr.traced()[0].explain(), "#2 in weak head normal form");
+    assert_eq!(mgr.traced()[1].explain(), "#0 ≡ #1");
+}
+
+// A generic step enum, whose fields' JSON impls depend on its parameter.
+mod generic {
+    use nanoda_macros::is_step;
+    use crate::trace::StepInfo;
+
+    #[is_step(json)]
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Gen<X> {
+        Collect { info : StepInfo, #[common] xs : Vec<X> },
+    }
+
+    impl GenJson for StepInfo {
+        fn to_json(&self) -> GenJsonValue {
+            GenJsonValue::Object(vec![("safety_idx".to_string(), self.safety_idx.to_json())])
+        }
+
+        fn from_json(value : &GenJsonValue) -> Result<Self, String> {
+            let safety_idx = value.get("safety_idx").ok_or("info has no `safety_idx`")?;
+            Ok(StepInfo::new(GenJson::from_json(safety_idx)?))
+        }
+    }
+
+    #[test]
+    fn json_lines() {
+        let step = Gen::Collect { info : StepInfo::new(3), xs : vec!["a".to_string(), "b".to_string()] };
+        let mut line = Vec::new();
+        step.write_json(&mut line).unwrap();
+        assert_eq!(Gen::read_json(std::str::from_utf8(&line).unwrap()).unwrap(), step);
+    }
+}
@@ -0,0 +1,300 @@
+// A small stand-in for the runtime `trace` module that the expansions of
+// `#[trace]` and `#[is_step]` refer to (`crate::trace` by default). Every
+// step a manager finishes is kept by its `VecTracer`, and every item is
+// kept as an `Expr` so the checker can resolve `#[item(Expr)]` fields.
+#![allow(dead_code)]
+
+use std::fmt;
+use std::ops::Index;
+use nanoda_macros::is_step;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr(pub String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ItemIdx(pub usize);
+
+#[derive(Default)]
+pub struct ItemStorage {
+    pub items : Vec<Expr>,
+}
+
+impl Index<ItemIdx> for ItemStorage {
+    type Output = Expr;
+    fn index(&self, idx : ItemIdx) -> &Expr {
+        &self.items[idx.0]
+    }
+}
+
+pub trait HasInsertItem {
+    fn insert_item(self, storage : &mut ItemStorage) -> ItemIdx;
+}
+
+fn insert_expr(expr : Expr, storage : &mut ItemStorage) -> ItemIdx {
+    storage.items.push(expr);
+    ItemIdx(storage.items.len() - 1)
+}
+
+impl HasInsertItem for Expr {
+    fn insert_item(self, storage : &mut ItemStorage) -> ItemIdx {
+        insert_expr(self, storage)
+    }
+}
+
+impl HasInsertItem for &Expr {
+    fn insert_item(self, storage : &mut ItemStorage) -> ItemIdx {
+        insert_expr(self.clone(), storage)
+    }
+}
+
+macro_rules! insert_by_debug {
+    ( $($t:ty),* ) => {
+        $(
+            impl HasInsertItem for $t {
+                fn insert_item(self, storage : &mut ItemStorage) -> ItemIdx {
+                    insert_expr(Expr(format!("{:?}", self)), storage)
+                }
+            }
+
+            impl HasInsertItem for &$t {
+                fn insert_item(self, storage : &mut ItemStorage) -> ItemIdx {
+                    insert_expr(Expr(format!("{:?}", self)), storage)
+                }
+            }
+        )*
+    };
+}
+
+insert_by_debug!(usize, bool, String, Vec<String>, Option<usize>, Result<usize, String>);
+
+// What an untraced function's `safety_idx = name` is bound as.
+pub type SafetyIdx = usize;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct StepInfo {
+    pub safety_idx : usize,
+    pub self_idx : Option<usize>,
+    pub result : Option<ItemIdx>,
+    pub children : Vec<usize>,
+    pub extras : Vec<usize>,
+    pub aborted : bool,
+    pub failed : bool,
+}
+
+impl StepInfo {
+    pub fn new(safety_idx : usize) -> Self {
+        StepInfo { safety_idx, ..StepInfo::default() }
+    }
+}
+
+#[is_step(codec(tests), json)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    #[short(EC)]
+    #[step_display("{l} ≡ {r}")]
+    EqCore { info : StepInfo, #[item(Expr)] l : ItemIdx, #[item(Expr)] r : ItemIdx },
+    #[short(W)]
+    #[step_explain("{e} in weak head normal form")]
+    Whnf { info : StepInfo, #[item(Expr)] e : ItemIdx },
+    #[step_display("failed on {1}")]
+    Fail(StepInfo, ItemIdx),
+    // Fields named like the locals of the generated visitor and checker.
+    Shadow { info : StepInfo, visitor : ItemIdx, checker : ItemIdx, #[item(Expr)] items : ItemIdx, result : ItemIdx },
+    Done,
+}
+
+impl Step {
+    fn info(&self) -> &StepInfo {
+        self.get_step_info().expect("traced steps have an info")
+    }
+
+    fn info_mut(&mut self) -> &mut StepInfo {
+        self.get_mut_step_info().expect("traced steps have an info")
+    }
+
+    pub fn get_safety_idx(&self) -> &usize {
+        &self.info().safety_idx
+    }
+
+    pub fn get_self_idx(&self) -> &Option<usize> {
+        &self.info().self_idx
+    }
+
+    pub fn get_mut_self_idx(&mut self) -> &mut Option<usize> {
+        &mut self.info_mut().self_idx
+    }
+
+    pub fn get_result(&self) -> &Option<ItemIdx> {
+        &self.info().result
+    }
+
+    pub fn get_mut_result(&mut self) -> &mut Option<ItemIdx> {
+        &mut self.info_mut().result
+    }
+
+    pub fn mark_aborted(&mut self) {
+        self.info_mut().aborted = true
+    }
+
+    pub fn mark_failed(&mut self) {
+        self.info_mut().failed = true
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Display for ItemIdx {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+impl StepCodec for ItemIdx {
+    fn encode_field(&self, w : &mut impl std::io::Write) -> std::io::Result<()> {
+        self.0.encode_field(w)
+    }
+
+    fn decode_field(r : &mut impl std::io::Read) -> std::io::Result<Self> {
+        usize::decode_field(r).map(ItemIdx)
+    }
+}
+
+impl StepCodec for StepInfo {
+    fn encode_field(&self, w : &mut impl std::io::Write) -> std::io::Result<()> {
+        self.safety_idx.encode_field(w)?;
+        self.self_idx.encode_field(w)?;
+        self.result.encode_field(w)?;
+        self.children.encode_field(w)?;
+        self.extras.encode_field(w)?;
+        self.aborted.encode_field(w)?;
+        self.failed.encode_field(w)
+    }
+
+    fn decode_field(r : &mut impl std::io::Read) -> std::io::Result<Self> {
+        Ok(StepInfo {
+            safety_idx : StepCodec::decode_field(r)?,
+            self_idx : StepCodec::decode_field(r)?,
+            result : StepCodec::decode_field(r)?,
+            children : StepCodec::decode_field(r)?,
+            extras : StepCodec::decode_field(r)?,
+            aborted : StepCodec::decode_field(r)?,
+            failed : StepCodec::decode_field(r)?,
+        })
+    }
+}
+
+impl StepJson for ItemIdx {
+    fn to_json(&self) -> StepJsonValue {
+        self.0.to_json()
+    }
+
+    fn from_json(value : &StepJsonValue) -> Result<Self, String> {
+        usize::from_json(value).map(ItemIdx)
+    }
+}
+
+impl StepJson for StepInfo {
+    fn to_json(&self) -> StepJsonValue {
+        StepJsonValue::Object(vec![
+            ("safety_idx".to_string(), self.safety_idx.to_json()),
+            ("idx".to_string(), self.self_idx.to_json()),
+            ("result".to_string(), self.result.to_json()),
+            ("children".to_string(), self.children.to_json()),
+            ("extras".to_string(), self.extras.to_json()),
+            ("aborted".to_string(), self.aborted.to_json()),
+            ("failed".to_string(), self.failed.to_json()),
+        ])
+    }
+
+    fn from_json(value : &StepJsonValue) -> Result<Self, String> {
+        let field = |name : &str| value.get(name).ok_or_else(|| format!("info has no `{}`", name));
+        Ok(StepInfo {
+            safety_idx : StepJson::from_json(field("safety_idx")?)?,
+            self_idx : StepJson::from_json(field("idx")?)?,
+            result : StepJson::from_json(field("result")?)?,
+            children : StepJson::from_json(field("children")?)?,
+            extras : StepJson::from_json(field("extras")?)?,
+            aborted : StepJson::from_json(field("aborted")?)?,
+            failed : StepJson::from_json(field("failed")?)?,
+        })
+    }
+}
+
+pub trait Tracer {
+    fn trace(&mut self, step : &Step);
+}
+
+#[derive(Default)]
+pub struct VecTracer(pub Vec<Step>);
+
+impl Tracer for VecTracer {
+    fn trace(&mut self, step : &Step) {
+        self.0.push(step.clone())
+    }
+}
+
+pub struct TraceMgr<T : Tracer> {
+    pub item_storage : ItemStorage,
+    pub stack : Vec<Step>,
+    pub tracer : T,
+    safety : usize,
+    steps : usize,
+}
+
+pub type Mgr = TraceMgr<VecTracer>;
+
+impl<T : Tracer> TraceMgr<T> {
+    pub fn new(tracer : T) -> Self {
+        TraceMgr { item_storage : ItemStorage::default(), stack : Vec::new(), tracer, safety : 0, steps : 0 }
+    }
+
+    pub fn next_safety_idx(&mut self) -> usize {
+        self.safety += 1;
+        self.safety
+    }
+
+    pub fn next_step_idx(&mut self) -> usize {
+        self.steps += 1;
+        self.steps - 1
+    }
+
+    pub fn stack_len(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn push(&mut self, step : Step) {
+        self.stack.push(step)
+    }
+
+    pub fn pop(&mut self) -> Step {
+        self.stack.pop().expect("popped an empty step stack")
+    }
+
+    pub fn add_child(&mut self, idx : usize, _child : &Step) {
+        if let Some(parent) = self.stack.last_mut() {
+            parent.info_mut().children.push(idx)
+        }
+    }
+
+    pub fn trace_step(&mut self, step : &Step) {
+        self.tracer.trace(step)
+    }
+
+    // `#[trace]` appends the safety index of the step being traced.
+    pub fn push_extra(&mut self, extra : usize, safety_idx : usize) {
+        let step = self.stack.iter_mut()
+                       .find(|step| *step.get_safety_idx() == safety_idx)
+                       .expect("push_extra for a step that isn't on the stack");
+        step.info_mut().extras.push(extra)
+    }
+}
+
+impl Mgr {
+    pub fn traced(&self) -> &[Step] {
+        &self.tracer.0
+    }
+}
@@ -0,0 +1,394 @@
+// What `#[trace]` records into the mock runtime in `trace`.
+use std::cell::RefCell;
+use std::panic::{ catch_unwind, AssertUnwindSafe };
+use parking_lot::{ Mutex, RwLock };
+use nanoda_macros::trace;
+
+mod trace;
+use crate::trace::{ Expr, Mgr, Step, TraceMgr, VecTracer };
+
+fn mgr() -> Mgr {
+    TraceMgr::new(VecTracer::default())
+}
+
+fn expr(s : &str) -> Expr {
+    Expr(s.to_string())
+}
+
+pub struct Tc {
+    pub mgr : RwLock<Mgr>,
+}
+
+impl Tc {
+    fn new() -> Self {
+        Tc { mgr : RwLock::new(mgr()) }
+    }
+
+    #[trace(self.mgr, EqCore(expr(a), expr(b)))]
+    pub fn eq(&self, a : &str, b : &str) -> bool {
+        self.mgr.write().push_extra(7);
+        a == b && self.whnf(a) == a.len()
+    }
+
+    #[trace(self.mgr, Whnf(expr(a)))]
+    pub fn whnf(&self, a : &str) -> usize {
+        if a.is_empty() {
+            return 0
+        }
+        a.len()
+    }
+
+    #[trace(self.mgr, Whnf(expr(a)))]
+    pub fn reject(&self, a : &str) -> usize {
+        if a == "boom" {
+            panic!("rejected")
+        }
+        self.whnf(a)
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn records_children_and_extras() {
+    let tc = Tc::new();
+    assert!(tc.eq("x", "x"));
+    let mgr = tc.mgr.read();
+    let traced = mgr.traced();
+    assert_eq!(traced.len(), 2);
+    assert!(matches!(traced[0], Step::Whnf { .. }));
+    assert_eq!(traced[1].get_step_info().unwrap().children, vec![0]);
+    assert_eq!(traced[1].get_step_info().unwrap().extras, vec![7]);
+    assert_eq!(mgr.item_storage[traced[1].get_result().unwrap()], expr("true"));
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn unwind_aborts_the_step_and_restores_the_stack() {
+    let tc = Tc::new();
+    assert!(catch_unwind(AssertUnwindSafe(|| tc.reject("boom"))).is_err());
+    assert_eq!(tc.mgr.read().stack_len(), 0);
+    {
+        let mgr = tc.mgr.read();
+        let info = mgr.traced()[0].get_step_info().unwrap();
+        assert!(info.aborted && info.result.is_none());
+    }
+    // Later calls trace as usual.
+    assert!(tc.eq("x", "x"));
+    assert_eq!(tc.mgr.read().traced().len(), 3);
+    assert_eq!(tc.mgr.read().stack_len(), 0);
+}
+
+impl Tc {
+    #[trace(self.mgr, Whnf(expr(a)), cfg = any())]
+    pub fn untraced(&self, a : &str) -> usize {
+        // Still gets a (placeholder) safety index.
+        if a.is_empty() {
+            self.mgr.write().push_extra(1);
+        }
+        a.len()
+    }
+}
+
+#[test]
+fn cfg_leaves_the_function_untraced() {
+    let tc = Tc::new();
+    assert_eq!(tc.untraced("abc"), 3);
+    assert!(tc.mgr.read().traced().is_empty());
+}
+
+impl Tc {
+    #[trace(self.mgr, Whnf(expr(a)), safety_idx = step_safety_idx)]
+    pub fn extra_safety_idx(&self, a : &str) -> usize {
+        self.mgr.write().push_extra(step_safety_idx);
+        a.len()
+    }
+
+    // Neither body pins down the type of `step_safety_idx`.
+    #[trace(self.mgr, Whnf(expr(a)), safety_idx = step_safety_idx, cfg = any())]
+    pub fn untraced_safety_idx(&self, a : &str) -> usize {
+        let _ = step_safety_idx;
+        a.len()
+    }
+
+    #[trace(self.mgr, Whnf(expr(a)), safety_idx = step_safety_idx, safety_idx_type = u64, cfg = any())]
+    pub fn untraced_safety_idx_type(&self, a : &str) -> u64 {
+        std::mem::size_of_val(&step_safety_idx) as u64 + a.len() as u64
+    }
+
+    // Names the expansion could have used for its own locals.
+    #[trace(self.mgr, Whnf(expr(this_step)))]
+    pub fn body_locals(&self, this_step : &str, result : usize) -> usize {
+        let write_guard = 5;
+        result + write_guard + this_step.len()
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn safety_idx() {
+    let tc = Tc::new();
+    assert_eq!(tc.extra_safety_idx("ab"), 2);
+    assert_eq!(tc.untraced_safety_idx("abc"), 3);
+    assert_eq!(tc.untraced_safety_idx_type("abc"), 11);
+    let mgr = tc.mgr.read();
+    assert_eq!(mgr.traced().len(), 1);
+    let info = mgr.traced()[0].get_step_info().unwrap();
+    assert_eq!(info.extras, vec![info.safety_idx]);
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn body_locals_dont_clash_with_the_expansion() {
+    let tc = Tc::new();
+    assert_eq!(tc.body_locals("ab", 1), 8);
+    assert_eq!(tc.mgr.read().traced().len(), 1);
+}
+
+pub struct CellTc {
+    pub mgr : RefCell<Mgr>,
+}
+
+impl CellTc {
+    #[trace(self.mgr, Whnf(expr(a)), access = refcell)]
+    pub fn whnf(&self, a : &str) -> usize {
+        if a.len() > 1 { self.whnf(&a[1..]) + 1 } else { 1 }
+    }
+}
+
+pub struct MutexTc {
+    pub mgr : Mutex<Mgr>,
+}
+
+impl MutexTc {
+    #[trace(self.mgr, Whnf(expr(a)), access = mutex)]
+    pub fn whnf(&self, a : &str) -> usize {
+        if a.len() > 1 { self.whnf(&a[1..]) + 1 } else { 1 }
+    }
+}
+
+#[trace(mgr, Whnf(expr(a)), access = direct)]
+pub fn whnf_direct(mgr : &mut Mgr, a : &str) -> usize {
+    if a == "boom" {
+        panic!("rejected")
+    }
+    if a.len() > 1 { whnf_direct(mgr, &a[1..]) + 1 } else { 1 }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn access_modes() {
+    let cell_tc = CellTc { mgr : RefCell::new(mgr()) };
+    assert_eq!(cell_tc.whnf("abc"), 3);
+    assert_eq!(cell_tc.mgr.borrow().traced().len(), 3);
+    assert_eq!(cell_tc.mgr.borrow().traced()[2].get_step_info().unwrap().children, vec![1]);
+
+    let mutex_tc = MutexTc { mgr : Mutex::new(mgr()) };
+    assert_eq!(mutex_tc.whnf("abc"), 3);
+    assert_eq!(mutex_tc.mgr.lock().traced().len(), 3);
+
+    let mut direct = mgr();
+    assert_eq!(whnf_direct(&mut direct, "abc"), 3);
+    assert_eq!(direct.traced().len(), 3);
+    assert!(catch_unwind(AssertUnwindSafe(|| whnf_direct(&mut direct, "boom"))).is_err());
+    assert_eq!(direct.stack_len(), 0);
+    assert!(direct.traced()[3].get_step_info().unwrap().aborted);
+    assert_eq!(*direct.traced()[3].get_result(), None);
+}
+
+impl Tc {
+    #[trace(self.mgr, Whnf(expr(a)), body = block)]
+    pub fn parse(&self, a : &str) -> Result<usize, String> {
+        if a.is_empty() {
+            return Err("empty".to_string())
+        }
+        let n : usize = a.parse().map_err(|e : std::num::ParseIntError| e.to_string())?;
+        Ok(n)
+    }
+
+    #[trace(self.mgr, Whnf(expr(a)), body = block)]
+    pub fn first(&self, a : &str) -> Option<usize> {
+        if a == "boom" {
+            panic!("rejected")
+        }
+        let c = a.chars().next()?;
+        Some(c as usize)
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn block_bodies_keep_early_exits() {
+    let tc = Tc::new();
+    assert_eq!(tc.parse(""), Err("empty".to_string()));
+    assert!(tc.parse("x").is_err());
+    assert_eq!(tc.parse("5"), Ok(5));
+    assert_eq!(tc.first(""), None);
+    assert_eq!(tc.first("a"), Some(97));
+    let mgr = tc.mgr.read();
+    assert_eq!(mgr.traced().len(), 5);
+    assert_eq!(mgr.stack_len(), 0);
+    assert!(mgr.traced().iter().all(|step| !step.get_step_info().unwrap().aborted));
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn unwinding_block_body_aborts_the_step() {
+    let tc = Tc::new();
+    assert!(catch_unwind(AssertUnwindSafe(|| tc.first("boom"))).is_err());
+    assert_eq!(tc.mgr.read().stack_len(), 0);
+    assert!(tc.mgr.read().traced()[0].get_step_info().unwrap().aborted);
+}
+
+impl Tc {
+    #[trace(self.mgr, Whnf(expr("")), result = ref)]
+    pub fn by_ref(&self) -> String {
+        "big".to_string()
+    }
+
+    #[trace(self.mgr, Whnf(expr("")), body = block, result = skip)]
+    pub fn iter(&self) -> impl Iterator<Item = usize> {
+        vec![1, 2].into_iter()
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn result_modes() {
+    let tc = Tc::new();
+    assert_eq!(tc.by_ref(), "big");
+    assert_eq!(tc.iter().sum::<usize>(), 3);
+    let mgr = tc.mgr.read();
+    assert_eq!(mgr.item_storage[mgr.traced()[0].get_result().unwrap()], expr("\"big\""));
+    assert_eq!(*mgr.traced()[1].get_result(), None);
+}
+
+impl Tc {
+    #[trace(self.mgr, Whnf(expr(a)), result = ok_only)]
+    pub fn ok_only(&self, a : &str) -> Result<usize, String> {
+        if a.is_empty() { Err("empty".to_string()) } else { Ok(a.len()) }
+    }
+
+    #[trace(self.mgr, Whnf(expr(a)), on_err = Fail(a.len()), body = block)]
+    pub fn swap_on_err(&self, a : &str) -> Option<usize> {
+        self.mgr.write().push_extra(4);
+        let c = a.chars().next()?;
+        Some(c as usize)
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn failures() {
+    let tc = Tc::new();
+    assert!(tc.ok_only("").is_err());
+    assert_eq!(tc.ok_only("ab"), Ok(2));
+    assert_eq!(tc.swap_on_err(""), None);
+    assert_eq!(tc.swap_on_err("a"), Some(97));
+    let mgr = tc.mgr.read();
+    let traced = mgr.traced();
+    let info = |idx : usize| traced[idx].get_step_info().unwrap();
+    assert!(info(0).failed && info(0).result.is_none());
+    assert!(!info(1).failed);
+    assert_eq!(mgr.item_storage[info(1).result.unwrap()], expr("2"));
+    // The replacement step takes over the original's info.
+    assert!(matches!(traced[2], Step::Fail(..)));
+    assert!(info(2).failed && info(2).extras == vec![4]);
+    assert!(matches!(traced[3], Step::Whnf { .. }) && !info(3).failed);
+}
+
+// A tracer whose body needs `&mut self`.
+pub struct CountingTc {
+    pub mgr : RwLock<Mgr>,
+    pub calls : usize,
+    pub names : Vec<String>,
+}
+
+impl CountingTc {
+    fn new() -> Self {
+        CountingTc { mgr : RwLock::new(mgr()), calls : 0, names : Vec::new() }
+    }
+
+    fn bump(&mut self) -> usize {
+        self.calls += 1;
+        self.calls
+    }
+
+    #[trace(self.mgr, Whnf(expr(a)))]
+    pub fn count(&mut self, a : &str) -> usize {
+        if a == "boom" {
+            panic!("rejected")
+        }
+        self.bump() + a.len()
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn mut_self_bodies() {
+    let mut tc = CountingTc::new();
+    assert_eq!(tc.count("ab"), 3);
+    assert!(catch_unwind(AssertUnwindSafe(|| tc.count("boom"))).is_err());
+    assert_eq!(tc.count("ab"), 4);
+    let mgr = tc.mgr.read();
+    assert_eq!(mgr.stack_len(), 0);
+    assert_eq!(mgr.traced().iter().map(|step| step.get_step_info().unwrap().aborted).collect::<Vec<_>>(), vec![false, true, false]);
+}
+
+impl CountingTc {
+    #[trace(self.mgr, Whnf(expr(a)), body = block)]
+    pub fn count_block(&mut self, a : &str) -> Option<usize> {
+        if a == "boom" {
+            panic!("rejected")
+        }
+        self.names.push(a.to_string());
+        let c = a.chars().next()?;
+        Some(self.bump() + c as usize)
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn mut_self_block_bodies() {
+    let mut tc = CountingTc::new();
+    assert_eq!(tc.count_block(""), None);
+    assert_eq!(tc.count_block("a"), Some(98));
+    assert!(catch_unwind(AssertUnwindSafe(|| tc.count_block("boom"))).is_err());
+    assert_eq!(tc.mgr.read().stack_len(), 0);
+    assert!(tc.mgr.read().traced()[2].get_step_info().unwrap().aborted);
+    assert_eq!(tc.names, vec!["".to_string(), "a".to_string()]);
+}
+
+// A tracer consumed by its traced method.
+pub struct Session<'m> {
+    pub mgr : &'m RwLock<Mgr>,
+    pub names : Vec<String>,
+}
+
+impl<'m> Session<'m> {
+    // Moves a field out of `self`; the tracer location is still there after.
+    #[trace(self.mgr, Whnf(expr(a)), body = block)]
+    pub fn into_names(self, a : &str) -> Vec<String> {
+        if a == "boom" {
+            panic!("rejected")
+        }
+        let mut names = self.names;
+        if a.is_empty() {
+            return names
+        }
+        names.push(a.to_string());
+        names
+    }
+}
+
+#[test]
+#[cfg_attr(feature = "no-trace", ignore = "`no-trace` records nothing")]
+fn self_consuming_block_bodies() {
+    let mgr = RwLock::new(mgr());
+    let session = |names : &[&str]| Session { mgr : &mgr, names : names.iter().map(|name| name.to_string()).collect() };
+    assert_eq!(session(&["x"]).into_names(""), vec!["x".to_string()]);
+    assert_eq!(session(&["x"]).into_names("y"), vec!["x".to_string(), "y".to_string()]);
+    assert!(catch_unwind(AssertUnwindSafe(|| session(&[]).into_names("boom"))).is_err());
+    let mgr = mgr.read();
+    assert_eq!(mgr.stack_len(), 0);
+    assert_eq!(mgr.traced().iter().map(|step| step.get_step_info().unwrap().aborted).collect::<Vec<_>>(), vec![false, false, true]);
+}